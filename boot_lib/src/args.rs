@@ -0,0 +1,743 @@
+use core::{
+    fmt,
+    mem::{align_of, size_of},
+    ptr, slice,
+};
+use uefi::table::boot::MemoryDescriptor;
+
+/// "TYTOBOOT" read as a little endian integer
+pub const KERNEL_ARGS_MAGIC: u64 = u64::from_le_bytes(*b"TYTOBOOT");
+
+/// Bumped whenever the header or the layout of an existing tag changes.
+/// Adding a new tag kind does not require a bump, unknown tags are skipped.
+pub const KERNEL_ARGS_VERSION: u32 = 2;
+
+const TAG_ALIGN: usize = 8;
+
+const fn align_up(x: usize, align: usize) -> usize {
+    (x + align - 1) & !(align - 1)
+}
+
+/// Header of the boot information block handed from the bootloader to the kernel.
+///
+/// The header is followed by tags, each starting with a [`TagHeader`] and
+/// aligned to 8 bytes. The list is terminated by a [`TagKind::END`] tag.
+/// Addresses are virtual unless the field name says otherwise.
+#[repr(C)]
+pub struct KernelArgs {
+    pub magic: u64,
+    pub version: u32,
+    /// `size_of::<KernelArgs>()` as seen by the bootloader
+    pub header_size: u32,
+    /// Size of the header and all tags
+    pub total_size: u64,
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TagKind(pub u32);
+
+impl TagKind {
+    pub const END: TagKind = TagKind(0);
+    /// [`MemoryMapInfo`] followed by the UEFI memory descriptors
+    pub const MEMORY_MAP: TagKind = TagKind(1);
+    /// [`FramebufferInfo`]
+    pub const FRAMEBUFFER: TagKind = TagKind(2);
+    /// [`UefiRuntimeInfo`]
+    pub const UEFI_RUNTIME: TagKind = TagKind(3);
+    /// [`AcpiRsdpInfo`]
+    pub const ACPI_RSDP: TagKind = TagKind(4);
+    /// [`SmbiosInfo`]
+    pub const SMBIOS: TagKind = TagKind(5);
+    /// The kernel command line as UTF-8, without a terminator
+    pub const COMMAND_LINE: TagKind = TagKind(6);
+    /// [`ModuleInfo`] followed by the module name as UTF-8, one tag per module
+    pub const MODULE: TagKind = TagKind(7);
+    /// [`LayoutInfo`]
+    pub const LAYOUT: TagKind = TagKind(8);
+    /// [`KernelDigestInfo`]
+    pub const KERNEL_DIGEST: TagKind = TagKind(9);
+    /// The bootloader's log as UTF-8, one record per line
+    pub const BOOT_LOG: TagKind = TagKind(10);
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TagHeader {
+    pub kind: TagKind,
+    /// Size of the payload, excluding this header and any padding
+    pub size: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryMapInfo {
+    pub entry_size: u32,
+    pub entry_count: u32,
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PixelFormat(pub u32);
+
+impl PixelFormat {
+    /// Byte order R, G, B, reserved
+    pub const RGB: PixelFormat = PixelFormat(0);
+    /// Byte order B, G, R, reserved
+    pub const BGR: PixelFormat = PixelFormat(1);
+    /// Layout described by the masks in [`FramebufferInfo`]
+    pub const BITMASK: PixelFormat = PixelFormat(2);
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FramebufferInfo {
+    pub address: u64,
+    /// Size in bytes
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scanline
+    pub stride: u32,
+    pub format: PixelFormat,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+}
+
+impl FramebufferInfo {
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.width as usize, self.height as usize)
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride as usize
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct UefiRuntimeInfo {
+    /// The runtime system table, already relocated by `SetVirtualAddressMap`
+    pub system_table: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct AcpiRsdpInfo {
+    pub phys_addr: u64,
+    /// 0 for an ACPI 1.0 RSDP, 2 for an ACPI 2.0+ RSDP that points to an XSDT
+    pub revision: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SmbiosInfo {
+    /// Physical address of the SMBIOS entry point structure
+    pub phys_addr: u64,
+    /// 3 for the 64-bit SMBIOS 3.0 entry point, 2 for the legacy 32-bit one
+    pub major_version: u32,
+}
+
+/// A file loaded by the bootloader, such as an initial ramdisk
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ModuleInfo {
+    pub phys_addr: u64,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// Where the bootloader placed the kernel, its stack and the physical memory
+/// window. The bases are randomized unless KASLR is disabled, the kernel's
+/// only if it is position independent.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct LayoutInfo {
+    /// Virtual address of physical address 0, all of physical memory is
+    /// mapped from there
+    pub phys_map_offset: u64,
+    /// Size of the physical memory window in bytes
+    pub phys_map_size: u64,
+    /// Initial stack pointer, the stack grows down from here
+    pub stack_bottom: u64,
+    /// Size of the stack in bytes
+    pub stack_size: u64,
+    /// Size of the unmapped guard area directly below the stack
+    pub stack_guard_size: u64,
+    /// Lowest address of the kernel image
+    pub kernel_base: u64,
+    /// Size of the kernel image in bytes
+    pub kernel_size: u64,
+    /// Difference between the address the kernel was loaded at and the
+    /// address it was linked at
+    pub kernel_slide: u64,
+    /// Non-zero if the kernel base was randomized, along with the others
+    pub randomized: u32,
+}
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Verification(pub u32);
+
+impl Verification {
+    /// There was no manifest, the digest was computed but not checked
+    pub const NONE: Verification = Verification(0);
+    /// The digest matched the manifest on the boot volume
+    pub const MANIFEST: Verification = Verification(1);
+    /// The digest matched a manifest signed with the key built into the bootloader
+    pub const SIGNED: Verification = Verification(2);
+}
+
+/// The SHA-256 digest of the kernel image as read from the boot volume
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct KernelDigestInfo {
+    pub sha256: [u8; 32],
+    pub verification: Verification,
+}
+
+impl fmt::Display for KernelDigestInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sha256:")?;
+        for byte in self.sha256 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        match self.verification {
+            Verification::NONE => f.write_str(" (unverified)"),
+            Verification::MANIFEST => f.write_str(" (verified)"),
+            Verification::SIGNED => f.write_str(" (verified, signed)"),
+            Verification(other) => write!(f, " (verification {})", other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum KernelArgsError {
+    BadMagic(u64),
+    VersionMismatch { loader: u32, kernel: u32 },
+    HeaderSizeMismatch { loader: u32, kernel: u32 },
+    Malformed,
+}
+
+impl fmt::Display for KernelArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelArgsError::BadMagic(magic) => {
+                write!(
+                    f,
+                    "bad boot info magic {:#x}, not loaded by a Tyto bootloader?",
+                    magic
+                )
+            }
+            KernelArgsError::VersionMismatch { loader, kernel } => write!(
+                f,
+                "bootloader speaks boot protocol v{}, the kernel expects v{}",
+                loader, kernel
+            ),
+            KernelArgsError::HeaderSizeMismatch { loader, kernel } => write!(
+                f,
+                "boot info header is {} bytes, the kernel expects {}",
+                loader, kernel
+            ),
+            KernelArgsError::Malformed => f.write_str("boot info tags are malformed"),
+        }
+    }
+}
+
+impl KernelArgs {
+    /// Checks that the block was produced by a compatible bootloader and that
+    /// every tag lies within it
+    pub fn validate(&self) -> Result<(), KernelArgsError> {
+        if self.magic != KERNEL_ARGS_MAGIC {
+            return Err(KernelArgsError::BadMagic(self.magic));
+        }
+
+        if self.version != KERNEL_ARGS_VERSION {
+            return Err(KernelArgsError::VersionMismatch {
+                loader: self.version,
+                kernel: KERNEL_ARGS_VERSION,
+            });
+        }
+
+        if self.header_size as usize != size_of::<KernelArgs>() {
+            return Err(KernelArgsError::HeaderSizeMismatch {
+                loader: self.header_size,
+                kernel: size_of::<KernelArgs>() as u32,
+            });
+        }
+
+        if (self.total_size as usize) < size_of::<KernelArgs>() + size_of::<TagHeader>() {
+            return Err(KernelArgsError::Malformed);
+        }
+
+        let mut tags = self.tags();
+        while tags.next().is_some() {}
+
+        if tags.terminated {
+            Ok(())
+        } else {
+            Err(KernelArgsError::Malformed)
+        }
+    }
+
+    pub fn tags(&self) -> Tags<'_> {
+        let len = self.total_size as usize - size_of::<KernelArgs>();
+        let rest = unsafe {
+            slice::from_raw_parts(
+                (self as *const KernelArgs as *const u8).add(size_of::<KernelArgs>()),
+                len,
+            )
+        };
+
+        Tags {
+            rest,
+            terminated: false,
+        }
+    }
+
+    pub fn find(&self, kind: TagKind) -> Option<Tag<'_>> {
+        self.tags().find(|tag| tag.kind == kind)
+    }
+
+    pub fn memory_map(&self) -> Option<&[MemoryDescriptor]> {
+        let tag = self.find(TagKind::MEMORY_MAP)?;
+        let info = tag.get::<MemoryMapInfo>()?;
+
+        if info.entry_size as usize != size_of::<MemoryDescriptor>() {
+            return None;
+        }
+
+        tag.entries::<MemoryMapInfo, MemoryDescriptor>(info.entry_count as usize)
+    }
+
+    pub fn framebuffer(&self) -> Option<&FramebufferInfo> {
+        self.find(TagKind::FRAMEBUFFER)?.get()
+    }
+
+    pub fn uefi_runtime(&self) -> Option<&UefiRuntimeInfo> {
+        self.find(TagKind::UEFI_RUNTIME)?.get()
+    }
+
+    pub fn acpi_rsdp(&self) -> Option<&AcpiRsdpInfo> {
+        self.find(TagKind::ACPI_RSDP)?.get()
+    }
+
+    pub fn smbios(&self) -> Option<&SmbiosInfo> {
+        self.find(TagKind::SMBIOS)?.get()
+    }
+
+    pub fn command_line(&self) -> Option<&str> {
+        core::str::from_utf8(self.find(TagKind::COMMAND_LINE)?.data).ok()
+    }
+
+    pub fn layout(&self) -> Option<&LayoutInfo> {
+        self.find(TagKind::LAYOUT)?.get()
+    }
+
+    pub fn boot_log(&self) -> Option<&str> {
+        core::str::from_utf8(self.find(TagKind::BOOT_LOG)?.data).ok()
+    }
+
+    pub fn kernel_digest(&self) -> Option<&KernelDigestInfo> {
+        self.find(TagKind::KERNEL_DIGEST)?.get()
+    }
+
+    /// Every module along with its name, skipping malformed tags
+    pub fn modules(&self) -> impl Iterator<Item = (&ModuleInfo, &str)> {
+        self.tags()
+            .filter(|tag| tag.kind == TagKind::MODULE)
+            .filter_map(|tag| {
+                let info = tag.get::<ModuleInfo>()?;
+                let name = core::str::from_utf8(&tag.data[size_of::<ModuleInfo>()..]).ok()?;
+                Some((info, name))
+            })
+    }
+}
+
+impl fmt::Debug for KernelArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "KernelArgs v{} ({} bytes) with tags: ",
+            self.version, self.total_size
+        ))?;
+
+        for tag in self.tags() {
+            f.write_fmt(format_args!("{:?} ({} bytes)\n", tag.kind, tag.data.len()))?
+        }
+
+        Result::Ok(())
+    }
+}
+
+pub struct Tag<'a> {
+    pub kind: TagKind,
+    pub data: &'a [u8],
+}
+
+impl<'a> Tag<'a> {
+    /// Reinterprets the start of the payload as `T`
+    pub fn get<T: Copy>(&self) -> Option<&'a T> {
+        if self.data.len() < size_of::<T>() || self.data.as_ptr() as usize % align_of::<T>() != 0 {
+            return None;
+        }
+
+        Some(unsafe { &*(self.data.as_ptr() as *const T) })
+    }
+
+    /// Returns `count` entries of type `E` following a `H` at the start of the payload
+    pub fn entries<H: Copy, E: Copy>(&self, count: usize) -> Option<&'a [E]> {
+        let offset = align_up(size_of::<H>(), align_of::<E>());
+        let len = count.checked_mul(size_of::<E>())?.checked_add(offset)?;
+
+        if self.data.len() < len || (self.data.as_ptr() as usize + offset) % align_of::<E>() != 0 {
+            return None;
+        }
+
+        let start = unsafe { self.data.as_ptr().add(offset) };
+        Some(unsafe { slice::from_raw_parts(start as *const E, count) })
+    }
+}
+
+pub struct Tags<'a> {
+    rest: &'a [u8],
+    terminated: bool,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.terminated || self.rest.len() < size_of::<TagHeader>() {
+            return None;
+        }
+
+        let header = unsafe { ptr::read_unaligned(self.rest.as_ptr() as *const TagHeader) };
+
+        if header.kind == TagKind::END {
+            self.terminated = true;
+            return None;
+        }
+
+        let end = size_of::<TagHeader>() + header.size as usize;
+        if end > self.rest.len() {
+            self.rest = &[];
+            return None;
+        }
+
+        let tag = Tag {
+            kind: header.kind,
+            data: &self.rest[size_of::<TagHeader>()..end],
+        };
+        self.rest = &self.rest[align_up(end, TAG_ALIGN).min(self.rest.len())..];
+
+        Some(tag)
+    }
+}
+
+/// Writes a boot information block into memory provided by the bootloader
+pub struct KernelArgsBuilder {
+    base: *mut u8,
+    capacity: usize,
+    len: usize,
+}
+
+impl KernelArgsBuilder {
+    /// Size taken by a tag with a payload of `payload` bytes, including padding
+    pub const fn tag_size(payload: usize) -> usize {
+        align_up(size_of::<TagHeader>() + payload, TAG_ALIGN)
+    }
+
+    /// Size taken by a tag holding a `H` followed by `count` entries of type `E`
+    pub const fn tag_size_with_entries<H, E>(count: usize) -> usize {
+        Self::tag_size(align_up(size_of::<H>(), align_of::<E>()) + count * size_of::<E>())
+    }
+
+    /// # Safety
+    ///
+    /// `base` must be 8-byte aligned and valid for writes of `capacity` bytes
+    /// for as long as the builder and the finished block are in use.
+    pub unsafe fn new(base: *mut u8, capacity: usize) -> Self {
+        assert_eq!(base as usize % TAG_ALIGN, 0);
+        assert!(capacity >= size_of::<KernelArgs>() + size_of::<TagHeader>());
+
+        Self {
+            base,
+            capacity,
+            len: size_of::<KernelArgs>(),
+        }
+    }
+
+    /// Appends a tag header and returns a pointer to its payload
+    fn reserve(&mut self, kind: TagKind, size: usize) -> *mut u8 {
+        let end = self.len + Self::tag_size(size);
+        assert!(end <= self.capacity, "Kernel args buffer too small");
+
+        unsafe {
+            let header = self.base.add(self.len);
+            (header as *mut TagHeader).write(TagHeader {
+                kind,
+                size: size as u32,
+            });
+            self.len = end;
+            header.add(size_of::<TagHeader>())
+        }
+    }
+
+    pub fn add<T: Copy>(&mut self, kind: TagKind, value: &T) {
+        let payload = self.reserve(kind, size_of::<T>());
+        unsafe { (payload as *mut T).write(*value) }
+    }
+
+    pub fn add_bytes(&mut self, kind: TagKind, bytes: &[u8]) {
+        let payload = self.reserve(kind, bytes.len());
+        unsafe { payload.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len()) }
+    }
+
+    /// Appends a tag holding `value` directly followed by `bytes`
+    pub fn add_with_bytes<T: Copy>(&mut self, kind: TagKind, value: &T, bytes: &[u8]) {
+        let payload = self.reserve(kind, size_of::<T>() + bytes.len());
+        unsafe {
+            (payload as *mut T).write(*value);
+            payload
+                .add(size_of::<T>())
+                .copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+        }
+    }
+
+    /// Appends a tag holding `value` followed by `entries`, and returns the
+    /// entries as they were written into the block
+    pub fn add_with_entries<T: Copy, E: Copy>(
+        &mut self,
+        kind: TagKind,
+        value: &T,
+        entries: impl ExactSizeIterator<Item = E>,
+    ) -> &mut [E] {
+        let count = entries.len();
+        let offset = align_up(size_of::<T>(), align_of::<E>());
+        let payload = self.reserve(kind, offset + count * size_of::<E>());
+
+        unsafe {
+            (payload as *mut T).write(*value);
+            let start = payload.add(offset) as *mut E;
+            let mut written = 0;
+            for entry in entries.take(count) {
+                start.add(written).write(entry);
+                written += 1;
+            }
+            assert_eq!(
+                written, count,
+                "Iterator yielded fewer entries than reported"
+            );
+
+            slice::from_raw_parts_mut(start, count)
+        }
+    }
+
+    /// Terminates the tag list and fills in the header
+    pub fn finish(mut self) -> *mut KernelArgs {
+        self.reserve(TagKind::END, 0);
+
+        let args = self.base as *mut KernelArgs;
+        unsafe {
+            args.write(KernelArgs {
+                magic: KERNEL_ARGS_MAGIC,
+                version: KERNEL_ARGS_VERSION,
+                header_size: size_of::<KernelArgs>() as u32,
+                total_size: self.len as u64,
+            });
+        }
+
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C, align(8))]
+    struct Buffer([u8; 512]);
+
+    fn build(add: impl FnOnce(&mut KernelArgsBuilder)) -> Box<Buffer> {
+        let mut buffer = Box::new(Buffer([0; 512]));
+        let mut builder = unsafe { KernelArgsBuilder::new(buffer.0.as_mut_ptr(), 512) };
+        add(&mut builder);
+        builder.finish();
+        buffer
+    }
+
+    fn args(buffer: &mut Buffer) -> &mut KernelArgs {
+        unsafe { &mut *(buffer.0.as_mut_ptr() as *mut KernelArgs) }
+    }
+
+    fn layout() -> LayoutInfo {
+        LayoutInfo {
+            phys_map_offset: 0xFFFF_8000_0000_0000,
+            phys_map_size: 0x1_0000_0000,
+            stack_bottom: 0xFFFF_DFFF_FFFF_F000,
+            stack_size: 0x10_0000,
+            stack_guard_size: 0x1000,
+            kernel_base: 0xFFFF_FFFF_8000_0000,
+            kernel_size: 0x20_0000,
+            kernel_slide: 0,
+            randomized: 0,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buffer = build(|builder| {
+            builder.add(TagKind::LAYOUT, &layout());
+            builder.add_bytes(TagKind::COMMAND_LINE, b"log=debug");
+            builder.add_with_bytes(
+                TagKind::MODULE,
+                &ModuleInfo {
+                    phys_addr: 0x1000,
+                    size: 3,
+                },
+                b"initrd",
+            );
+            builder.add_bytes(TagKind::BOOT_LOG, b"one\ntwo\n");
+        });
+        let args = args(&mut buffer);
+
+        assert!(args.validate().is_ok());
+        assert_eq!(args.layout().unwrap().kernel_size, 0x20_0000);
+        assert_eq!(args.command_line(), Some("log=debug"));
+        assert_eq!(args.boot_log(), Some("one\ntwo\n"));
+        assert!(args.framebuffer().is_none());
+
+        let modules: Vec<_> = args.modules().collect();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].0.phys_addr, 0x1000);
+        assert_eq!(modules[0].1, "initrd");
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let mut buffer = build(|builder| {
+            let written = builder.add_with_entries(
+                TagKind::MEMORY_MAP,
+                &MemoryMapInfo {
+                    entry_size: 8,
+                    entry_count: 3,
+                },
+                [1u64, 2, 3].into_iter(),
+            );
+            assert_eq!(written, [1, 2, 3]);
+        });
+        let args = args(&mut buffer);
+        let tag = args.find(TagKind::MEMORY_MAP).unwrap();
+
+        assert_eq!(
+            tag.entries::<MemoryMapInfo, u64>(3),
+            Some(&[1u64, 2, 3][..])
+        );
+        assert_eq!(tag.entries::<MemoryMapInfo, u64>(4), None);
+        assert_eq!(tag.entries::<MemoryMapInfo, u64>(usize::MAX), None);
+    }
+
+    #[test]
+    fn unknown_tags_are_skipped() {
+        let mut buffer = build(|builder| {
+            builder.add_bytes(TagKind(0x1234), &[0xFF; 13]);
+            builder.add(TagKind::LAYOUT, &layout());
+        });
+        let args = args(&mut buffer);
+
+        assert!(args.validate().is_ok());
+        assert!(args.layout().is_some());
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut buffer = build(|_| {});
+
+        args(&mut buffer).magic = 0;
+        assert!(matches!(
+            args(&mut buffer).validate(),
+            Err(KernelArgsError::BadMagic(0))
+        ));
+
+        args(&mut buffer).magic = KERNEL_ARGS_MAGIC;
+        args(&mut buffer).version = KERNEL_ARGS_VERSION + 1;
+        assert!(matches!(
+            args(&mut buffer).validate(),
+            Err(KernelArgsError::VersionMismatch { .. })
+        ));
+
+        args(&mut buffer).version = KERNEL_ARGS_VERSION;
+        args(&mut buffer).header_size += 8;
+        assert!(matches!(
+            args(&mut buffer).validate(),
+            Err(KernelArgsError::HeaderSizeMismatch { .. })
+        ));
+
+        args(&mut buffer).header_size -= 8;
+        args(&mut buffer).total_size = size_of::<KernelArgs>() as u64;
+        assert!(matches!(
+            args(&mut buffer).validate(),
+            Err(KernelArgsError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn truncated_tags() {
+        let mut buffer = build(|builder| builder.add_bytes(TagKind::COMMAND_LINE, b"abc"));
+
+        // Without the end tag
+        let total = args(&mut buffer).total_size;
+        args(&mut buffer).total_size = total - size_of::<TagHeader>() as u64;
+        assert!(matches!(
+            args(&mut buffer).validate(),
+            Err(KernelArgsError::Malformed)
+        ));
+        args(&mut buffer).total_size = total;
+
+        // A payload running past the end of the block
+        let size = size_of::<KernelArgs>() + 4;
+        buffer.0[size..size + 4].copy_from_slice(&1000u32.to_ne_bytes());
+        assert!(matches!(
+            args(&mut buffer).validate(),
+            Err(KernelArgsError::Malformed)
+        ));
+        assert!(args(&mut buffer).command_line().is_none());
+    }
+
+    #[test]
+    fn misaligned_and_short_payloads() {
+        let buffer = Buffer([0; 512]);
+
+        let tag = Tag {
+            kind: TagKind::LAYOUT,
+            data: &buffer.0[1..1 + size_of::<LayoutInfo>()],
+        };
+        assert!(tag.get::<LayoutInfo>().is_none());
+        assert!(tag.entries::<u8, u64>(1).is_none());
+
+        let tag = Tag {
+            kind: TagKind::LAYOUT,
+            data: &buffer.0[..size_of::<LayoutInfo>() - 1],
+        };
+        assert!(tag.get::<LayoutInfo>().is_none());
+
+        // The entries would start past the end of the payload
+        let tag = Tag {
+            kind: TagKind::MEMORY_MAP,
+            data: &buffer.0[..4],
+        };
+        assert!(tag.entries::<MemoryMapInfo, u64>(0).is_none());
+    }
+
+    #[test]
+    fn tag_sizes() {
+        assert_eq!(KernelArgsBuilder::tag_size(0), 8);
+        assert_eq!(KernelArgsBuilder::tag_size(1), 16);
+        assert_eq!(
+            KernelArgsBuilder::tag_size_with_entries::<u32, u64>(2),
+            8 + 8 + 16
+        );
+    }
+}
@@ -1,14 +1,11 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_efiapi)]
 
-use arrayvec::ArrayVec;
-use core::fmt;
-use uefi::{
-    proto::console::gop::ModeInfo,
-    table::{boot::MemoryDescriptor, Runtime, SystemTable},
-};
+mod args;
 
-pub const KERNEL_ARGS_MDL_SIZE: u64 = 512;
+pub use args::*;
+
+use core::ops::Range;
 
 pub const KERNEL_MEM_TYPE_RANGE_START: u32 = 0x80000000;
 pub const KERNEL_RX_MEM_TYPE: u32 = 0x80000001;
@@ -18,26 +15,31 @@ pub const KERNEL_RWX_MEM_TYPE: u32 = 0x80000004;
 pub const KERNEL_STACK_MEM_TYPE: u32 = 0x80000005;
 pub const PTE_MEM_TYPE: u32 = 0x80000006;
 pub const KERNEL_ARGS_MEM_TYPE: u32 = 0x80000007;
-pub const PHYS_MAP_OFFSET: u64 = 0xFFFFFFF000000000;
+pub const MODULE_MEM_TYPE: u32 = 0x80000008;
 pub const KERNEL_STACK_SIZE_PAGES: u64 = 256;
-pub const KERNEL_STACK_BOTTOM: u64 = 0xFFFFFFF000000000 - 0x1000;
-
-#[repr(C)]
-pub struct KernelArgs {
-    pub mmap: ArrayVec<MemoryDescriptor, 512>,
-    pub uefi_rst: SystemTable<Runtime>,
-    pub framebuffer_addr: *mut u8,
-    pub framebuffer_info: ModeInfo,
-}
+/// Unmapped pages directly below the kernel stack, so an overflow faults
+pub const KERNEL_STACK_GUARD_PAGES: u64 = 1;
+/// Written over the lowest `KERNEL_STACK_CANARY_WORDS` words of the kernel
+/// stack, the kernel checks that it stays intact
+pub const KERNEL_STACK_CANARY: u64 = u64::from_le_bytes(*b"STACKEND");
+pub const KERNEL_STACK_CANARY_WORDS: usize = 8;
 
-impl fmt::Debug for KernelArgs {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("KernelArgs with MDL: ")?;
+/// Everything the kernel maps lives from here up. The low half belongs to user
+/// processes, the kernel unmaps it once it has taken over.
+pub const HIGHER_HALF_START: u64 = 0xFFFF800000000000;
 
-        for i in self.mmap.iter() {
-            f.write_fmt(format_args!("{:?}\n", i))?
-        }
+// Layout used when KASLR is disabled, each at the edge of its KASLR range.
+// The kernel reads the actual layout from the `LAYOUT` tag and must not rely
+// on these.
+pub const DEFAULT_PHYS_MAP_OFFSET: u64 = 0xFFFF800000000000;
+pub const DEFAULT_KERNEL_STACK_BOTTOM: u64 = 0xFFFFE00000000000 - 0x1000;
 
-        Result::Ok(())
-    }
-}
+// Ranges the randomized bases are chosen from. They do not overlap each other
+// or the firmware's identity map.
+pub const KASLR_PHYS_MAP_RANGE: Range<u64> = 0xFFFF800000000000..0xFFFFC00000000000;
+pub const KASLR_PHYS_MAP_ALIGN: u64 = 0x40000000;
+pub const KASLR_STACK_RANGE: Range<u64> = 0xFFFFC00000000000..0xFFFFE00000000000;
+pub const KASLR_STACK_ALIGN: u64 = 0x1000;
+/// The top 2 GiB, where the kernel is linked
+pub const KASLR_KERNEL_RANGE: Range<u64> = 0xFFFFFFFF80000000..0xFFFFFFFFC0000000;
+pub const KASLR_KERNEL_ALIGN: u64 = 0x200000;
@@ -0,0 +1,125 @@
+use core::{
+    arch::x86_64::_rdtsc,
+    fmt::{self, Write},
+};
+use log::{LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use uefi::prelude::*;
+
+/// Bytes of log kept for the kernel, anything after that only reaches the
+/// console and COM1
+pub(crate) const BOOT_LOG_CAPACITY: usize = 64 * 1024;
+
+const COM1: u16 = 0x3F8;
+
+static LOGGER: BootLogger = BootLogger(Mutex::new(None));
+static mut BUFFER: [u8; BOOT_LOG_CAPACITY] = [0; BOOT_LOG_CAPACITY];
+
+/// Writes every record, with the time since the bootloader started, to the
+/// UEFI console, COM1 and a buffer that is handed to the kernel
+struct BootLogger(Mutex<Option<Sinks>>);
+
+struct Sinks {
+    /// Gone once boot services are exited
+    console: Option<SystemTable<Boot>>,
+    serial: SerialPort,
+    buffer: &'static mut [u8; BOOT_LOG_CAPACITY],
+    len: usize,
+    full: bool,
+    tsc_start: u64,
+    tsc_per_us: u64,
+}
+
+// The bootloader runs on a single CPU and never logs from event callbacks
+unsafe impl Send for Sinks {}
+
+impl Write for Sinks {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(st) = self.console.as_ref() {
+            let _ = st.stdout().write_str(s);
+        }
+
+        let _ = self.serial.write_str(s);
+
+        // Pieces are dropped whole so the buffer stays valid UTF-8
+        if !self.full && self.len + s.len() <= BOOT_LOG_CAPACITY {
+            self.buffer[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+        } else {
+            self.full = true;
+        }
+
+        Ok(())
+    }
+}
+
+impl Log for BootLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if let Some(sinks) = self.0.lock().as_mut() {
+            let us = (rdtsc() - sinks.tsc_start) / sinks.tsc_per_us;
+            let _ = writeln!(
+                sinks,
+                "[{:>4}.{:06}] [{}] {}",
+                us / 1_000_000,
+                us % 1_000_000,
+                record.level().as_str().chars().next().unwrap(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Starts logging. Timestamps count from here, so this should be the first
+/// thing the bootloader does.
+pub(crate) fn init(st: &SystemTable<Boot>) {
+    // Calibrate the TSC against the firmware's stall
+    let tsc_start = rdtsc();
+    st.boot_services().stall(10_000);
+    let tsc_per_us = ((rdtsc() - tsc_start) / 10_000).max(1);
+
+    let mut serial = unsafe { SerialPort::new(COM1) };
+    serial.init();
+
+    *LOGGER.0.lock() = Some(Sinks {
+        console: Some(unsafe { st.unsafe_clone() }),
+        serial,
+        buffer: unsafe { &mut BUFFER },
+        len: 0,
+        full: false,
+        tsc_start,
+        tsc_per_us,
+    });
+
+    log::set_logger(&LOGGER).expect("A logger is already set");
+    log::set_max_level(LevelFilter::Info);
+}
+
+/// Stops writing to the UEFI console, which goes away with boot services
+pub(crate) fn exit_boot_services() {
+    if let Some(sinks) = LOGGER.0.lock().as_mut() {
+        sinks.console = None;
+    }
+}
+
+/// Calls `f` with everything logged so far
+pub(crate) fn with_contents<R>(f: impl FnOnce(&[u8]) -> R) -> R {
+    match LOGGER.0.lock().as_ref() {
+        Some(sinks) => f(&sinks.buffer[..sinks.len]),
+        None => f(&[]),
+    }
+}
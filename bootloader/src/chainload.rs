@@ -0,0 +1,187 @@
+use alloc::{string::String, vec::Vec};
+use core::{ffi::c_void, fmt, fmt::Write, iter, slice};
+use log::{info, warn};
+use uefi::{
+    prelude::*,
+    proto::{console::text::Color, device_path::DevicePath, Protocol},
+    table::boot::LoadImageSource,
+    unsafe_guid,
+};
+
+use crate::{
+    config::BootEntry,
+    fs, menu, net,
+    verify::{Manifest, VerifyError},
+};
+
+// Device path node types and subtypes
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_FILE_PATH: u8 = 0x04;
+const END_DEVICE_PATH: u8 = 0x7F;
+const END_ENTIRE_DEVICE_PATH: u8 = 0xFF;
+
+/// EFI_LOADED_IMAGE_PROTOCOL with writable fields, uefi-rs has no way to set
+/// the device of an image loaded from a buffer
+#[repr(C)]
+#[unsafe_guid("5b1b31a1-9562-11d2-8e3f-00a0c969723b")]
+#[derive(Protocol)]
+struct RawLoadedImage {
+    revision: u32,
+    parent_handle: Handle,
+    system_table: *const c_void,
+    device_handle: Option<Handle>,
+    file_path: *const c_void,
+    reserved: *const c_void,
+    load_options_size: u32,
+    load_options: *const c_void,
+    image_base: *const c_void,
+    image_size: u64,
+    image_code_type: u32,
+    image_data_type: u32,
+    unload: *const c_void,
+}
+
+#[derive(Debug)]
+pub(crate) enum ChainloadError {
+    NotFound(String),
+    Verify(VerifyError),
+    Load(Status),
+}
+
+impl fmt::Display for ChainloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainloadError::NotFound(path) => write!(f, "{} not found", path),
+            ChainloadError::Verify(e) => write!(f, "{}", e),
+            ChainloadError::Load(status) => {
+                write!(f, "the firmware could not load it ({:?})", status)
+            }
+        }
+    }
+}
+
+/// The device path of `path` on the boot volume, the volume's own device path
+/// followed by a file path node. Boot managers find their other files
+/// relative to it.
+fn file_device_path(image: Handle, st: &SystemTable<Boot>, path: &str) -> Option<Vec<u8>> {
+    let device = fs::boot_device(image, st);
+    let device_path = st
+        .boot_services()
+        .handle_protocol::<DevicePath>(device)
+        .ok()?;
+
+    let mut bytes = Vec::new();
+    unsafe {
+        let mut node = device_path.get() as *const u8;
+        loop {
+            let len = u16::from_le_bytes([*node.add(2), *node.add(3)]) as usize;
+            if *node == END_DEVICE_PATH && *node.add(1) == END_ENTIRE_DEVICE_PATH {
+                break;
+            }
+            if len < 4 {
+                return None;
+            }
+
+            bytes.extend_from_slice(slice::from_raw_parts(node, len));
+            node = node.add(len);
+        }
+    }
+
+    let name = path
+        .encode_utf16()
+        .chain(iter::once(0))
+        .collect::<Vec<u16>>();
+    bytes.extend_from_slice(&[MEDIA_DEVICE_PATH, MEDIA_FILE_PATH]);
+    bytes.extend_from_slice(&((4 + name.len() * 2) as u16).to_le_bytes());
+    bytes.extend(name.iter().flat_map(|c| c.to_le_bytes()));
+    bytes.extend_from_slice(&[END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH, 4, 0]);
+
+    Some(bytes)
+}
+
+/// Starts the EFI application of `entry` and returns once it exits. The image
+/// is checked against the manifest, then loaded from memory so the firmware
+/// runs exactly what was checked.
+fn start(
+    image: Handle,
+    st: &SystemTable<Boot>,
+    entry: &BootEntry,
+    manifest: &Manifest,
+) -> Result<Status, ChainloadError> {
+    let bs = st.boot_services();
+
+    let data = fs::read_file(image, st, &entry.kernel)
+        .ok_or_else(|| ChainloadError::NotFound(entry.kernel.clone()))?;
+    let digest = manifest
+        .check(&entry.kernel, &data)
+        .map_err(ChainloadError::Verify)?;
+
+    info!("Starting {} ({})", entry.kernel, digest);
+
+    if !entry.modules.is_empty() {
+        warn!("Modules are ignored for EFI applications");
+    }
+
+    // Downloaded applications have no file on a local volume to point at
+    let file_path = if net::is_network_path(&entry.kernel) {
+        None
+    } else {
+        file_device_path(image, st, &entry.kernel)
+    };
+
+    let child = bs
+        .load_image(
+            image,
+            LoadImageSource::FromBuffer {
+                buffer: &data,
+                file_path: file_path
+                    .as_ref()
+                    .map(|path| unsafe { &*(path.as_ptr() as *const DevicePath) }),
+            },
+        )
+        .map_err(|e| ChainloadError::Load(e.status()))?;
+
+    // The command line as a NUL terminated UCS-2 string, it has to outlive
+    // the application
+    let options = entry
+        .cmdline
+        .encode_utf16()
+        .chain(iter::once(0))
+        .collect::<Vec<u16>>();
+
+    // Applications find their files through the device they were loaded from
+    unsafe {
+        let loaded_image = &mut *bs
+            .handle_protocol::<RawLoadedImage>(child)
+            .expect("Failed to open the loaded image protocol")
+            .get();
+        loaded_image.device_handle = Some(fs::boot_device(image, st));
+        loaded_image.load_options_size = (options.len() * 2) as u32;
+        loaded_image.load_options = options.as_ptr().cast();
+    }
+
+    let status = match bs.start_image(child) {
+        Ok(()) => Status::SUCCESS,
+        Err(e) => e.status(),
+    };
+
+    info!("{} exited ({:?})", entry.kernel, status);
+
+    Ok(status)
+}
+
+/// Runs an EFI application entry. If it cannot be started the reason is
+/// shown until a key is pressed, either way the caller goes back to the menu.
+pub(crate) fn run(image: Handle, st: &SystemTable<Boot>, entry: &BootEntry, manifest: &Manifest) {
+    if let Err(e) = start(image, st, entry, manifest) {
+        warn!("Could not start {}: {}", entry.name, e);
+
+        let stdout = st.stdout();
+        let _ = stdout.set_color(Color::LightRed, Color::Black);
+        let _ = writeln!(stdout, "\nCould not start {}: {}", entry.name, e);
+        let _ = stdout.set_color(Color::LightGray, Color::Black);
+        let _ = writeln!(stdout, "Press any key to return to the menu");
+
+        menu::wait_for_key(st);
+    }
+}
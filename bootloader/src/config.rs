@@ -0,0 +1,279 @@
+use alloc::{borrow::ToOwned, string::String, vec, vec::Vec};
+use core::str;
+use log::{info, warn};
+use uefi::prelude::*;
+
+use crate::{fs, loader::KERNEL_PATH};
+
+pub(crate) const CONFIG_PATH: &str = "\\EFI\\tyto\\boot.cfg";
+
+/// Options read from the boot configuration file.
+///
+/// The file consists of `key = value` lines, `#` starts a comment line.
+/// Everything after the first `=` is the value, so the command line can
+/// contain `=` itself. A `[name]` line starts a boot entry, the `type`,
+/// `kernel`, `cmdline` and `module` lines after it belong to that entry.
+/// Without any sections those lines describe a single entry.
+#[derive(Debug)]
+pub(crate) struct BootConfig {
+    /// Preferred screen resolution, `resolution = 1280x720`
+    pub(crate) resolution: Option<(usize, usize)>,
+    /// Randomize the kernel layout, `kaslr = off` for deterministic debugging
+    pub(crate) kaslr: bool,
+    /// Seconds before the menu boots the selected entry, `timeout = 5`
+    pub(crate) timeout: Option<u64>,
+    /// Name of the entry selected initially, `default = <name>`. The last
+    /// entry booted from the menu is used if there is none.
+    pub(crate) default: Option<String>,
+    /// Never empty
+    pub(crate) entries: Vec<BootEntry>,
+}
+
+/// What a boot entry starts, `type = tyto` or `type = efi`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EntryKind {
+    /// A Tyto kernel, loaded and handed `KernelArgs`
+    Tyto,
+    /// Another EFI application, started with `StartImage`. Control comes
+    /// back to the menu if it exits.
+    Efi,
+}
+
+/// A kernel image and what to pass to it
+#[derive(Debug, Clone)]
+pub(crate) struct BootEntry {
+    pub(crate) name: String,
+    pub(crate) kind: EntryKind,
+    /// Path of the kernel image, or the EFI application, `kernel = <path>`
+    pub(crate) kernel: String,
+    /// Passed to the kernel verbatim, `cmdline = log=debug timer_hz=100`. EFI
+    /// applications get it as their load options.
+    pub(crate) cmdline: String,
+    /// Files handed to the kernel as modules, one `module = <path>` line each
+    pub(crate) modules: Vec<String>,
+}
+
+impl BootEntry {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            kind: EntryKind::Tyto,
+            kernel: KERNEL_PATH.to_owned(),
+            cmdline: String::new(),
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            resolution: None,
+            kaslr: true,
+            timeout: None,
+            default: None,
+            entries: vec![BootEntry::new("Tyto")],
+        }
+    }
+}
+
+impl BootConfig {
+    /// Reads the configuration from the boot volume, falling back to the
+    /// defaults if there is none
+    pub(crate) fn load(image: Handle, st: &SystemTable<Boot>) -> Self {
+        let data = match fs::read_file(image, st, CONFIG_PATH) {
+            Some(data) => data,
+            None => {
+                info!("No {}, using defaults", CONFIG_PATH);
+                return Self::default();
+            }
+        };
+
+        match str::from_utf8(&data) {
+            Ok(text) => Self::parse(text),
+            Err(e) => {
+                warn!("{} is not valid UTF-8 ({}), ignoring it", CONFIG_PATH, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub(crate) fn parse(text: &str) -> Self {
+        let mut config = Self::default();
+        // Entry lines before the first section describe the implicit entry,
+        // which the sections replace
+        let mut sections = false;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if !sections {
+                    config.entries.clear();
+                    sections = true;
+                }
+                config.entries.push(BootEntry::new(name.trim()));
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    warn!("boot.cfg:{}: expected `key = value`", number + 1);
+                    continue;
+                }
+            };
+
+            match key {
+                "resolution" => match parse_resolution(value) {
+                    Some(resolution) => config.resolution = Some(resolution),
+                    None => warn!("boot.cfg:{}: bad resolution {:?}", number + 1, value),
+                },
+                "kaslr" => match parse_bool(value) {
+                    Some(kaslr) => config.kaslr = kaslr,
+                    None => warn!("boot.cfg:{}: expected on or off", number + 1),
+                },
+                "timeout" => match value.parse() {
+                    Ok(timeout) => config.timeout = Some(timeout),
+                    Err(_) => warn!("boot.cfg:{}: bad timeout {:?}", number + 1, value),
+                },
+                "default" => config.default = Some(value.to_owned()),
+                "type" => match value {
+                    "tyto" => config.entry().kind = EntryKind::Tyto,
+                    "efi" => config.entry().kind = EntryKind::Efi,
+                    _ => warn!("boot.cfg:{}: expected tyto or efi", number + 1),
+                },
+                "kernel" => config.entry().kernel = value.to_owned(),
+                "cmdline" => config.entry().cmdline = value.to_owned(),
+                "module" => config.entry().modules.push(value.to_owned()),
+                _ => warn!("boot.cfg:{}: unknown option {:?}", number + 1, key),
+            }
+        }
+
+        config
+    }
+
+    /// The entry that entry specific lines currently apply to
+    fn entry(&mut self) -> &mut BootEntry {
+        self.entries.last_mut().unwrap()
+    }
+}
+
+/// Parses `<width>x<height>`
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "on" | "yes" | "true" | "1" => Some(true),
+        "off" | "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_is_default() {
+        let config = BootConfig::parse("");
+
+        assert_eq!(config.resolution, None);
+        assert!(config.kaslr);
+        assert_eq!(config.entries.len(), 1);
+        assert_eq!(config.entries[0].kernel, KERNEL_PATH);
+    }
+
+    #[test]
+    fn global_options() {
+        let config = BootConfig::parse(
+            "# comment\n\
+             resolution = 1280x720\n\
+             kaslr = off\n\
+             timeout = 5\n\
+             default = Debug\n",
+        );
+
+        assert_eq!(config.resolution, Some((1280, 720)));
+        assert!(!config.kaslr);
+        assert_eq!(config.timeout, Some(5));
+        assert_eq!(config.default.as_deref(), Some("Debug"));
+    }
+
+    #[test]
+    fn bad_values_and_unknown_keys_are_ignored() {
+        let config = BootConfig::parse(
+            "resolution = big\n\
+             kaslr = maybe\n\
+             timeout = -1\n\
+             colour = blue\n\
+             no equals sign\n\
+             type = linux\n",
+        );
+
+        assert_eq!(config.resolution, None);
+        assert!(config.kaslr);
+        assert_eq!(config.timeout, None);
+        assert_eq!(config.entries.len(), 1);
+        assert_eq!(config.entries[0].kind, EntryKind::Tyto);
+    }
+
+    #[test]
+    fn value_keeps_equals_signs() {
+        let config = BootConfig::parse("cmdline = log=debug timer_hz=100");
+
+        assert_eq!(config.entries[0].cmdline, "log=debug timer_hz=100");
+    }
+
+    #[test]
+    fn implicit_entry() {
+        let config = BootConfig::parse(
+            "kernel = \\kernel.elf\n\
+             module = \\a\n\
+             module = \\b\n",
+        );
+
+        assert_eq!(config.entries.len(), 1);
+        assert_eq!(config.entries[0].kernel, "\\kernel.elf");
+        assert_eq!(config.entries[0].modules, ["\\a", "\\b"]);
+    }
+
+    #[test]
+    fn sections_replace_the_implicit_entry() {
+        let config = BootConfig::parse(
+            "cmdline = dropped\n\
+             timeout = 3\n\
+             [ Tyto ]\n\
+             cmdline = log=trace\n\
+             [Shell]\n\
+             type = efi\n\
+             kernel = \\EFI\\shell.efi\n\
+             timeout = 7\n",
+        );
+
+        assert_eq!(config.entries.len(), 2);
+        assert_eq!(config.entries[0].name, "Tyto");
+        assert_eq!(config.entries[0].kind, EntryKind::Tyto);
+        assert_eq!(config.entries[0].kernel, KERNEL_PATH);
+        assert_eq!(config.entries[0].cmdline, "log=trace");
+        assert_eq!(config.entries[1].name, "Shell");
+        assert_eq!(config.entries[1].kind, EntryKind::Efi);
+        assert_eq!(config.entries[1].kernel, "\\EFI\\shell.efi");
+        // Global options apply wherever they are
+        assert_eq!(config.timeout, Some(7));
+    }
+
+    #[test]
+    fn resolution() {
+        assert_eq!(parse_resolution("800x600"), Some((800, 600)));
+        assert_eq!(parse_resolution("800 x 600"), Some((800, 600)));
+        assert_eq!(parse_resolution("800"), None);
+        assert_eq!(parse_resolution("x600"), None);
+    }
+}
@@ -0,0 +1,143 @@
+use alloc::{vec, vec::Vec};
+use core::slice;
+use uefi::{
+    prelude::*,
+    proto::{
+        loaded_image::LoadedImage,
+        media::{
+            file::{Directory, File, FileAttribute, FileMode, FileType, RegularFile},
+            fs::SimpleFileSystem,
+        },
+    },
+    table::boot::{AllocateType, MemoryType},
+};
+use x86_64::{
+    align_up,
+    structures::paging::{PageSize, Size4KiB},
+};
+
+use crate::net;
+
+/// The device the bootloader was loaded from
+pub(crate) fn boot_device(image: Handle, st: &SystemTable<Boot>) -> Handle {
+    let loaded_image = unsafe {
+        &*st.boot_services()
+            .handle_protocol::<LoadedImage>(image)
+            .expect("Failed to open the loaded image protocol")
+            .get()
+    };
+
+    loaded_image.device()
+}
+
+/// Opens the root directory of the volume the bootloader was loaded from
+fn open_boot_volume(image: Handle, st: &SystemTable<Boot>) -> Directory {
+    let fs = unsafe {
+        &mut *st
+            .boot_services()
+            .handle_protocol::<SimpleFileSystem>(boot_device(image, st))
+            .expect("Failed to open the boot volume file system")
+            .get()
+    };
+
+    fs.open_volume().expect("Failed to open the boot volume")
+}
+
+/// Opens a regular file on the boot volume, returns `None` if it does not exist
+fn open_file(image: Handle, st: &SystemTable<Boot>, path: &str) -> Option<RegularFile> {
+    let mut root = open_boot_volume(image, st);
+
+    let handle = root
+        .open(path, FileMode::Read, FileAttribute::empty())
+        .ok()?;
+
+    match handle.into_type().expect("Failed to query file type") {
+        FileType::Regular(file) => Some(file),
+        FileType::Dir(_) => None,
+    }
+}
+
+fn file_size(file: &mut RegularFile) -> usize {
+    file.set_position(RegularFile::END_OF_FILE)
+        .expect("Failed to seek to the end of file");
+    let size = file.get_position().expect("Failed to get file size") as usize;
+    file.set_position(0).expect("Failed to rewind file");
+
+    size
+}
+
+/// Reads until `buf` is full or the file ends, returns the number of bytes read
+fn read_all(file: &mut RegularFile, buf: &mut [u8]) -> usize {
+    let mut done = 0;
+
+    while done < buf.len() {
+        match file.read(&mut buf[done..]).expect("Failed to read file") {
+            0 => break,
+            read => done += read,
+        }
+    }
+
+    done
+}
+
+/// Reads a whole file from the boot volume, or over TFTP for `tftp://`
+/// paths. Returns `None` if it does not exist.
+pub(crate) fn read_file(image: Handle, st: &SystemTable<Boot>, path: &str) -> Option<Vec<u8>> {
+    if net::is_network_path(path) {
+        return net::read_file(st, path);
+    }
+
+    let mut file = open_file(image, st, path)?;
+
+    let mut buf = vec![0; file_size(&mut file)];
+    let read = read_all(&mut file, &mut buf);
+    buf.truncate(read);
+
+    Some(buf)
+}
+
+/// Allocates pages of `mem_type` for `size` bytes, returns them as a slice
+/// through the firmware's identity map
+fn allocate_pages(st: &SystemTable<Boot>, size: usize, mem_type: MemoryType) -> &'static mut [u8] {
+    let pages = align_up(size as u64, Size4KiB::SIZE) / Size4KiB::SIZE;
+    let phys = st
+        .boot_services()
+        .allocate_pages(AllocateType::AnyPages, mem_type, pages as _)
+        .expect("Could not allocate memory for a file");
+
+    unsafe { slice::from_raw_parts_mut(phys as *mut u8, size) }
+}
+
+/// Reads a whole file, like [`read_file`], into freshly allocated pages of
+/// `mem_type`, returns the physical address and size of the contents
+pub(crate) fn read_file_to_pages(
+    image: Handle,
+    st: &SystemTable<Boot>,
+    path: &str,
+    mem_type: MemoryType,
+) -> Option<(u64, usize)> {
+    // TFTP cannot write straight into the pages without knowing the size
+    // first, so the download is copied over
+    if net::is_network_path(path) {
+        let data = net::read_file(st, path)?;
+        if data.is_empty() {
+            return Some((0, 0));
+        }
+
+        let buf = allocate_pages(st, data.len(), mem_type);
+        buf.copy_from_slice(&data);
+        return Some((buf.as_ptr() as u64, buf.len()));
+    }
+
+    let mut file = open_file(image, st, path)?;
+    let size = file_size(&mut file);
+
+    if size == 0 {
+        return Some((0, 0));
+    }
+
+    let buf = allocate_pages(st, size, mem_type);
+    let read = read_all(&mut file, buf);
+
+    Some((buf.as_ptr() as u64, read))
+}
@@ -0,0 +1,184 @@
+use core::{mem::size_of, ops::Range, ptr};
+use log::{info, warn};
+use uefi::{prelude::*, proto::Protocol, unsafe_guid, Guid};
+use x86_64::{align_down, align_up, instructions::random::RdRand};
+
+/// The EFI_RNG_PROTOCOL, which uefi-rs does not provide
+#[repr(C)]
+#[unsafe_guid("3152bca5-eade-433d-862e-c01cdc291f44")]
+#[derive(Protocol)]
+struct Rng {
+    get_info: unsafe extern "efiapi" fn(
+        this: &Rng,
+        algorithm_list_size: *mut usize,
+        algorithm_list: *mut Guid,
+    ) -> Status,
+    get_rng: unsafe extern "efiapi" fn(
+        this: &Rng,
+        algorithm: *const Guid,
+        value_length: usize,
+        value: *mut u8,
+    ) -> Status,
+}
+
+impl Rng {
+    /// Fills a `u64` using the firmware's default algorithm
+    fn get_u64(&self) -> Option<u64> {
+        let mut value = 0u64;
+        let status = unsafe {
+            (self.get_rng)(
+                self,
+                ptr::null(),
+                size_of::<u64>(),
+                &mut value as *mut u64 as *mut u8,
+            )
+        };
+
+        status.is_success().then(|| value)
+    }
+}
+
+enum Entropy {
+    Firmware(&'static Rng),
+    RdRand(RdRand),
+}
+
+impl Entropy {
+    /// Prefers the firmware RNG, falls back to RDRAND
+    fn find(st: &SystemTable<Boot>) -> Option<Self> {
+        let firmware = st
+            .boot_services()
+            .locate_protocol::<Rng>()
+            .ok()
+            .map(|rng| unsafe { &*rng.get() })
+            // Some implementations are present but cannot produce anything
+            .filter(|rng| rng.get_u64().is_some());
+
+        if let Some(rng) = firmware {
+            info!("Using the firmware RNG for KASLR");
+            return Some(Entropy::Firmware(rng));
+        }
+
+        let rdrand = RdRand::new()?;
+        info!("No usable firmware RNG, using RDRAND for KASLR");
+        Some(Entropy::RdRand(rdrand))
+    }
+
+    fn next_u64(&self) -> u64 {
+        match self {
+            Entropy::Firmware(rng) => rng.get_u64().expect("The firmware RNG failed"),
+            // RDRAND can run dry for a moment, Intel recommends 10 retries
+            Entropy::RdRand(rdrand) => (0..10)
+                .find_map(|_| rdrand.get_u64())
+                .expect("RDRAND failed to produce a random number"),
+        }
+    }
+}
+
+/// Chooses the bases of the kernel layout
+pub(crate) struct Kaslr {
+    entropy: Option<Entropy>,
+}
+
+impl Kaslr {
+    /// Looks for an entropy source if `enabled`. KASLR stays off without one.
+    pub(crate) fn new(st: &SystemTable<Boot>, enabled: bool) -> Self {
+        if !enabled {
+            info!("KASLR disabled by the boot config");
+            return Self { entropy: None };
+        }
+
+        let entropy = Entropy::find(st);
+        if entropy.is_none() {
+            warn!("No entropy source, KASLR disabled");
+        }
+
+        Self { entropy }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.entropy.is_some()
+    }
+
+    /// Returns a random `align` aligned base such that `size` bytes starting
+    /// there lie within `range`, or `default` if KASLR is disabled
+    pub(crate) fn choose(&self, range: Range<u64>, size: u64, align: u64, default: u64) -> u64 {
+        match &self.entropy {
+            Some(entropy) => place(range, size, align, entropy.next_u64()),
+            None => default,
+        }
+    }
+}
+
+/// The `align` aligned base for `size` bytes within `range` that `random`
+/// selects
+fn place(range: Range<u64>, size: u64, align: u64, random: u64) -> u64 {
+    let start = align_up(range.start, align);
+    let end = align_down(range.end.saturating_sub(size), align);
+    assert!(
+        start <= end,
+        "{:#x} bytes do not fit in the KASLR range {:#x?}",
+        size,
+        range
+    );
+
+    let slots = (end - start) / align + 1;
+    start + random % slots * align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boot_lib::{
+        KASLR_KERNEL_ALIGN, KASLR_KERNEL_RANGE, KASLR_PHYS_MAP_ALIGN, KASLR_PHYS_MAP_RANGE,
+        KASLR_STACK_ALIGN, KASLR_STACK_RANGE,
+    };
+
+    #[test]
+    fn bounds() {
+        let range = 0x1000..0x10000;
+
+        assert_eq!(place(range.clone(), 0x2000, 0x1000, 0), 0x1000);
+        // The last slot ends exactly at the end of the range
+        assert_eq!(place(range.clone(), 0x2000, 0x1000, 13), 0xE000);
+        // Wraps around past the last slot
+        assert_eq!(place(range.clone(), 0x2000, 0x1000, 14), 0x1000);
+        assert_eq!(place(range, 0xF000, 0x1000, u64::MAX), 0x1000);
+    }
+
+    #[test]
+    fn alignment() {
+        for random in 0..64 {
+            let base = place(0x1234..0x100000, 0x3000, 0x4000, random);
+
+            assert_eq!(base % 0x4000, 0);
+            assert!(base >= 0x1234 && base + 0x3000 <= 0x100000);
+        }
+    }
+
+    #[test]
+    fn layout_ranges() {
+        let ranges = [
+            (KASLR_PHYS_MAP_RANGE, 0x100_0000_0000, KASLR_PHYS_MAP_ALIGN),
+            (KASLR_STACK_RANGE, 0x10_0000, KASLR_STACK_ALIGN),
+            (KASLR_KERNEL_RANGE, 0x100_0000, KASLR_KERNEL_ALIGN),
+        ];
+
+        for (range, size, align) in ranges {
+            for random in [0, 1, u64::MAX / 3, u64::MAX] {
+                let base = place(range.clone(), size, align, random);
+
+                assert!(
+                    range.start <= base && base - range.start <= range.end - range.start - size
+                );
+                assert_eq!(base % align, 0);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_large() {
+        place(0x1000..0x2000, 0x2000, 0x1000, 0);
+    }
+}
@@ -0,0 +1,291 @@
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use boot_lib::{
+    ModuleInfo, HIGHER_HALF_START, KASLR_KERNEL_ALIGN, KASLR_KERNEL_RANGE, KERNEL_RO_MEM_TYPE,
+    KERNEL_RW_MEM_TYPE, KERNEL_RX_MEM_TYPE, MODULE_MEM_TYPE,
+};
+use core::slice;
+use goblin::elf::{
+    header::{EM_X86_64, ET_DYN, ET_EXEC},
+    program_header::{ProgramHeader, PF_W, PF_X, PT_LOAD},
+    reloc::R_X86_64_RELATIVE,
+    Elf,
+};
+use log::{info, warn};
+use uefi::{
+    prelude::*,
+    table::boot::{AllocateType, MemoryType},
+};
+use x86_64::{
+    structures::paging::{
+        Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{fs, kaslr::Kaslr, UefiAlloc};
+
+/// Used by boot entries without a `kernel` line
+pub(crate) const KERNEL_PATH: &str = "\\EFI\\tyto\\kernel.elf";
+
+pub(crate) struct LoadedKernel {
+    pub(crate) entry: VirtAddr,
+    /// Lowest address of the image
+    pub(crate) base: VirtAddr,
+    /// Size of the image in bytes
+    pub(crate) size: u64,
+    /// Difference between the load address and the link address
+    pub(crate) slide: u64,
+    /// Whether the base was chosen by KASLR rather than taken from the image
+    pub(crate) randomized: bool,
+}
+
+pub(crate) struct LoadedModule {
+    pub(crate) info: ModuleInfo,
+    /// The path on the boot volume
+    pub(crate) path: String,
+    /// The file name without the directory
+    pub(crate) name: String,
+}
+
+impl LoadedModule {
+    pub(crate) fn data(&self) -> &[u8] {
+        if self.info.size == 0 {
+            return &[];
+        }
+
+        // Physical memory is still identity mapped by the firmware
+        unsafe { slice::from_raw_parts(self.info.phys_addr as *const u8, self.info.size as _) }
+    }
+}
+
+/// Loads every module that exists, a missing module does not stop the boot
+pub(crate) fn load_modules(
+    image: Handle,
+    st: &SystemTable<Boot>,
+    paths: &[String],
+) -> Vec<LoadedModule> {
+    paths
+        .iter()
+        .filter_map(|path| {
+            let mem_type = MemoryType::custom(MODULE_MEM_TYPE);
+            let (phys_addr, size) = match fs::read_file_to_pages(image, st, path, mem_type) {
+                Some(module) => module,
+                None => {
+                    warn!("Module {} not found, skipping it", path);
+                    return None;
+                }
+            };
+
+            info!(
+                "Loaded module {} ({} bytes) at {:#x}",
+                path, size, phys_addr
+            );
+
+            Some(LoadedModule {
+                info: ModuleInfo {
+                    phys_addr,
+                    size: size as u64,
+                },
+                path: path.clone(),
+                name: path
+                    .rsplit(|c| c == '\\' || c == '/')
+                    .next()
+                    .unwrap_or(path)
+                    .to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Loads every PT_LOAD segment of the kernel image and maps it. A position
+/// independent kernel is moved to a base chosen by `kaslr` and relocated,
+/// anything else is mapped at its link address.
+pub(crate) unsafe fn load_kernel(
+    image: &[u8],
+    st: &SystemTable<Boot>,
+    page_table: &mut OffsetPageTable,
+    kaslr: &Kaslr,
+) -> LoadedKernel {
+    let elf = Elf::parse(image).expect("Failed to parse the kernel image");
+
+    assert!(
+        elf.is_64 && elf.little_endian && elf.header.e_machine == EM_X86_64,
+        "The kernel image is not a little-endian x86_64 ELF"
+    );
+    assert!(
+        elf.header.e_type == ET_EXEC || elf.header.e_type == ET_DYN,
+        "The kernel image is not an executable"
+    );
+
+    let segments = || elf.program_headers.iter().filter(|ph| ph.p_type == PT_LOAD);
+
+    // Check every segment before anything is mapped
+    if let Some(ph) = segments().find(|ph| ph.p_flags & (PF_W | PF_X) == PF_W | PF_X) {
+        panic!(
+            "Refusing to boot: kernel segment at {:#x} is both writable and executable",
+            ph.p_vaddr
+        );
+    }
+
+    let link_start = segments()
+        .map(|ph| VirtAddr::new(ph.p_vaddr).align_down(Size4KiB::SIZE))
+        .min()
+        .expect("The kernel image has no loadable segments");
+    let link_end = segments()
+        .map(|ph| (VirtAddr::new(ph.p_vaddr) + ph.p_memsz).align_up(Size4KiB::SIZE))
+        .max()
+        .unwrap();
+    let size = link_end - link_start;
+
+    let randomized = elf.header.e_type == ET_DYN && kaslr.is_enabled();
+    let base = if elf.header.e_type == ET_DYN {
+        VirtAddr::new(kaslr.choose(
+            KASLR_KERNEL_RANGE,
+            size,
+            KASLR_KERNEL_ALIGN,
+            link_start.as_u64(),
+        ))
+    } else {
+        if kaslr.is_enabled() {
+            warn!("The kernel is not position independent, loading it at its link address");
+        }
+        link_start
+    };
+
+    // The kernel drops the low half once it runs
+    assert!(
+        base.as_u64() >= HIGHER_HALF_START,
+        "Refusing to boot: the kernel would be loaded at {:?}, below the higher half",
+        base
+    );
+
+    // May wrap, the slide is applied with wrapping arithmetic
+    let slide = base.as_u64().wrapping_sub(link_start.as_u64());
+
+    info!("Loading kernel at {:?} (slide {:#x})", base, slide);
+
+    for ph in segments() {
+        load_segment(image, ph, slide, st, page_table);
+    }
+
+    relocate(&elf, slide, page_table);
+
+    LoadedKernel {
+        entry: VirtAddr::new(elf.entry.wrapping_add(slide)),
+        base,
+        size,
+        slide,
+        randomized,
+    }
+}
+
+/// Applies the dynamic relocations of a position independent kernel. Only
+/// `R_X86_64_RELATIVE` is supported, the kernel has no symbols to resolve.
+unsafe fn relocate(elf: &Elf, slide: u64, page_table: &OffsetPageTable) {
+    assert!(
+        elf.dynrels.is_empty() && elf.pltrelocs.is_empty(),
+        "The kernel image has unsupported relocations"
+    );
+
+    for reloc in elf.dynrelas.iter() {
+        assert_eq!(
+            reloc.r_type, R_X86_64_RELATIVE,
+            "Unsupported kernel relocation type {}",
+            reloc.r_type
+        );
+
+        let target = VirtAddr::new(reloc.r_offset.wrapping_add(slide));
+        let phys = page_table
+            .translate_addr(target)
+            .expect("Kernel relocation outside of the image");
+        let value = (reloc.r_addend.unwrap_or(0) as u64).wrapping_add(slide);
+
+        // Read-only segments are written through the firmware's identity map
+        (phys.as_u64() as *mut u64).write_unaligned(value);
+    }
+
+    if !elf.dynrelas.is_empty() {
+        info!("Applied {} kernel relocations", elf.dynrelas.len());
+    }
+}
+
+/// Returns the memory type and page flags for a segment, so that code is RX,
+/// read-only data is RO and everything else is RW+NX
+fn segment_protection(ph: &ProgramHeader) -> (u32, PageTableFlags) {
+    let executable = ph.p_flags & PF_X != 0;
+    let writable = ph.p_flags & PF_W != 0;
+
+    match (executable, writable) {
+        (true, false) => (KERNEL_RX_MEM_TYPE, PageTableFlags::PRESENT),
+        (false, true) => (
+            KERNEL_RW_MEM_TYPE,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        ),
+        (false, false) => (
+            KERNEL_RO_MEM_TYPE,
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+        ),
+        (true, true) => unreachable!("W+X segments are rejected before loading"),
+    }
+}
+
+unsafe fn load_segment(
+    image: &[u8],
+    ph: &ProgramHeader,
+    slide: u64,
+    st: &SystemTable<Boot>,
+    page_table: &mut OffsetPageTable,
+) {
+    assert!(ph.p_filesz <= ph.p_memsz, "Malformed kernel segment");
+    // Permissions are applied per page, so segments must not share pages
+    assert!(
+        VirtAddr::new(ph.p_vaddr).is_aligned(Size4KiB::SIZE),
+        "Kernel segment at {:#x} is not page aligned",
+        ph.p_vaddr
+    );
+
+    let (mem_type, flags) = segment_protection(ph);
+
+    let virt = VirtAddr::new(ph.p_vaddr.wrapping_add(slide));
+    let virt_start = virt.align_down(Size4KiB::SIZE);
+    let virt_end = (virt + ph.p_memsz).align_up(Size4KiB::SIZE);
+    let pages = (virt_end - virt_start) / Size4KiB::SIZE;
+
+    info!(
+        "Loading segment {:?} - {:?} ({} pages) as {:?}",
+        virt_start, virt_end, pages, flags
+    );
+
+    let phys = st
+        .boot_services()
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::custom(mem_type),
+            pages as _,
+        )
+        .expect("Could not allocate memory for a kernel segment");
+
+    let file_range = ph.file_range();
+    let data = image
+        .get(file_range)
+        .expect("Kernel segment lies outside of the image");
+
+    // Physical memory is still identity mapped by the firmware
+    let dst = phys as *mut u8;
+    dst.write_bytes(0, (pages * Size4KiB::SIZE) as _);
+    dst.add((virt - virt_start) as _)
+        .copy_from_nonoverlapping(data.as_ptr(), data.len());
+
+    for i in 0..pages {
+        page_table
+            .map_to_with_table_flags(
+                Page::<Size4KiB>::from_start_address(virt_start + i * Size4KiB::SIZE).unwrap(),
+                PhysFrame::from_start_address(PhysAddr::new(phys + i * Size4KiB::SIZE)).unwrap(),
+                flags,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                &mut UefiAlloc {},
+            )
+            .expect("Failed to map kernel segment")
+            .flush();
+    }
+}
@@ -3,12 +3,22 @@
 #![no_std]
 #![no_main]
 #![feature(abi_efiapi)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
-use arrayvec::ArrayVec;
-
-use log::info;
+mod boot_log;
+mod chainload;
+mod config;
+mod fs;
+mod kaslr;
+mod loader;
+mod menu;
+mod net;
+mod platform;
+mod verify;
+
+use log::{error, info, warn};
 use uefi::{
     prelude::*,
     table::boot::{AllocateType, MemoryType},
@@ -16,35 +26,42 @@ use uefi::{
 
 use x86_64::{
     align_up,
-    registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Efer},
+    registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Efer, EferFlags},
     structures::paging::{FrameAllocator, PageTable, PageTableFlags, PhysFrame, Size4KiB},
     PhysAddr, VirtAddr,
 };
 
 use alloc::{borrow::ToOwned, format, vec, vec::Vec};
 use boot_lib::{
-    KernelArgs, KERNEL_ARGS_MEM_TYPE, KERNEL_STACK_BOTTOM, KERNEL_STACK_MEM_TYPE,
-    KERNEL_STACK_SIZE_PAGES, PHYS_MAP_OFFSET, PTE_MEM_TYPE,
+    AcpiRsdpInfo, FramebufferInfo, KernelArgs, KernelArgsBuilder, KernelDigestInfo, LayoutInfo,
+    MemoryMapInfo, ModuleInfo, PixelFormat, SmbiosInfo, TagKind, UefiRuntimeInfo,
+    DEFAULT_KERNEL_STACK_BOTTOM, DEFAULT_PHYS_MAP_OFFSET, KASLR_PHYS_MAP_ALIGN,
+    KASLR_PHYS_MAP_RANGE, KASLR_STACK_ALIGN, KASLR_STACK_RANGE, KERNEL_ARGS_MEM_TYPE,
+    KERNEL_STACK_CANARY, KERNEL_STACK_CANARY_WORDS, KERNEL_STACK_GUARD_PAGES,
+    KERNEL_STACK_MEM_TYPE, KERNEL_STACK_SIZE_PAGES, PTE_MEM_TYPE,
 };
-use core::{
-    arch::asm,
-    iter::FromIterator,
-    mem::{size_of, MaybeUninit},
-    ptr::addr_of_mut,
+use core::{alloc::Layout, arch::asm, cmp::Reverse, mem::size_of, panic::PanicInfo};
+use uefi::{
+    proto::console::gop::{self, FrameBuffer, GraphicsOutput, ModeInfo, PixelFormat::BltOnly},
+    table::boot::MemoryDescriptor,
 };
-use uefi::proto::console::gop::{FrameBuffer, GraphicsOutput, ModeInfo, PixelFormat::Bgr};
 use x86_64::structures::paging::{
     mapper::{MapToError, TranslateResult},
     Mapper, OffsetPageTable, Page, PageSize, Size1GiB, Size2MiB, Translate,
 };
 
-use kernel::kernel_main;
+/// Room for descriptors created by allocations made after the map size is queried
+const MMAP_SLACK_ENTRIES: usize = 32;
+
+/// Set on entry, for code that cannot be handed the system table
+static mut SYSTEM_TABLE: Option<SystemTable<Boot>> = None;
 
-struct UefiAlloc;
+pub(crate) struct UefiAlloc;
 
 unsafe impl FrameAllocator<Size4KiB> for UefiAlloc {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let addr = unsafe { uefi_services::system_table().as_mut() }
+        let addr = unsafe { SYSTEM_TABLE.as_ref() }
+            .expect("Boot services are not available")
             .boot_services()
             .allocate_pages(AllocateType::AnyPages, MemoryType::custom(PTE_MEM_TYPE), 1)
             .expect("Failed to allocate a page");
@@ -154,6 +171,9 @@ unsafe fn map_offset<A: FrameAllocator<Size4KiB>>(
     assert_eq!(done, pages);
 }
 
+/// Maps `size_pages` fresh pages directly below `bottom` and writes the stack
+/// canary at their lowest address. The caller keeps the pages below that
+/// unmapped as a guard.
 unsafe fn map_stack<M>(
     bottom: VirtAddr,
     size_pages: u64,
@@ -172,13 +192,20 @@ unsafe fn map_stack<M>(
         )
         .expect("Could not allocate memory for the kernel stack");
 
+    // Physical memory is still identity mapped by the firmware
+    (mem as *mut u64).write_bytes(0, (size_pages * Size4KiB::SIZE / 8) as _);
+    for i in 0..KERNEL_STACK_CANARY_WORDS {
+        (mem as *mut u64).add(i).write(KERNEL_STACK_CANARY);
+    }
+
     for i in 0..size_pages {
         mapper
             .map_to_with_table_flags(
-                Page::containing_address(bottom - Size4KiB::SIZE * i),
-                PhysFrame::containing_address(PhysAddr::new(
-                    mem + Size4KiB::SIZE * (size_pages - i),
-                )),
+                Page::from_start_address(bottom - Size4KiB::SIZE * (i + 1)).unwrap(),
+                PhysFrame::from_start_address(PhysAddr::new(
+                    mem + Size4KiB::SIZE * (size_pages - 1 - i),
+                ))
+                .unwrap(),
                 PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE | PageTableFlags::WRITABLE,
                 PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
                 &mut UefiAlloc {},
@@ -188,44 +215,113 @@ unsafe fn map_stack<M>(
     }
 }
 
-fn init_framebuffer(system_table: &mut SystemTable<Boot>) -> (FrameBuffer<'static>, ModeInfo) {
-    let gop = unsafe {
-        system_table
-            .boot_services()
-            .locate_protocol::<GraphicsOutput>()
-            .expect("Failed to locate graphics output protocol")
-            .get()
-            .as_mut()
-            .unwrap()
+/// Sets the preferred resolution if the firmware offers it, the largest mode
+/// otherwise. Returns `None` if there is no usable graphics output, in which
+/// case the kernel runs headless.
+fn init_framebuffer(
+    system_table: &mut SystemTable<Boot>,
+    preferred: Option<(usize, usize)>,
+) -> Option<(FrameBuffer<'static>, ModeInfo)> {
+    let gop = match system_table
+        .boot_services()
+        .locate_protocol::<GraphicsOutput>()
+    {
+        Ok(gop) => unsafe { &mut *gop.get() },
+        Err(e) => {
+            warn!("No graphics output protocol ({:?})", e.status());
+            return None;
+        }
     };
 
-    let mode = [(1920, 1080), (1920, 1200), (1280, 720), (640, 480)]
-        .into_iter()
-        .find_map(|i| {
-            for mode in gop.modes().collect::<Vec<_>>() {
-                let info = mode.info();
-                if info.resolution() == i && info.pixel_format() == Bgr {
-                    match gop.set_mode(&mode) {
-                        Ok(_) => return Some(*mode.info()),
-                        Err(_) => return None,
-                    }
-                }
+    // The kernel needs direct access to the pixels
+    let mut modes = gop
+        .modes()
+        .filter(|mode| mode.info().pixel_format() != BltOnly)
+        .collect::<Vec<_>>();
+
+    modes.sort_by_key(|mode| {
+        let (width, height) = mode.info().resolution();
+        (Some((width, height)) != preferred, Reverse(width * height))
+    });
+
+    if let Some((width, height)) = preferred {
+        if modes.first().map(|mode| mode.info().resolution()) != Some((width, height)) {
+            warn!("Resolution {}x{} is not available", width, height);
+        }
+    }
+
+    for mode in modes {
+        let info = *mode.info();
+        match gop.set_mode(&mode) {
+            Ok(_) => {
+                info!(
+                    "Using {}x{} {:?} graphics mode",
+                    info.resolution().0,
+                    info.resolution().1,
+                    info.pixel_format()
+                );
+                return Some((gop.frame_buffer(), info));
             }
-            None
-        })
-        .expect("Failed to find a suitable framebuffer mode");
+            Err(e) => warn!(
+                "Failed to set {:?} graphics mode ({:?})",
+                info.resolution(),
+                e.status()
+            ),
+        }
+    }
+
+    warn!("No usable graphics mode");
+    None
+}
+
+/// Describes the framebuffer in the boot protocol's own terms
+fn framebuffer_info(
+    framebuffer: &mut FrameBuffer,
+    mode: &ModeInfo,
+    phys_map_offset: u64,
+) -> FramebufferInfo {
+    let (width, height) = mode.resolution();
+    let (format, mask) = match mode.pixel_format() {
+        gop::PixelFormat::Rgb => (PixelFormat::RGB, None),
+        gop::PixelFormat::Bgr => (PixelFormat::BGR, None),
+        gop::PixelFormat::Bitmask => (PixelFormat::BITMASK, mode.pixel_bitmask()),
+        gop::PixelFormat::BltOnly => unreachable!("BltOnly modes are never selected"),
+    };
+
+    FramebufferInfo {
+        address: framebuffer.as_mut_ptr() as u64 + phys_map_offset,
+        size: framebuffer.size() as u64,
+        width: width as u32,
+        height: height as u32,
+        stride: mode.stride() as u32,
+        format,
+        red_mask: mask.map_or(0, |m| m.red),
+        green_mask: mask.map_or(0, |m| m.green),
+        blue_mask: mask.map_or(0, |m| m.blue),
+    }
+}
+
+/// Returns the size of a buffer able to hold the current memory map plus
+/// `MMAP_SLACK_ENTRIES` descriptors, and that number of descriptors
+fn memory_map_capacity(st: &SystemTable<Boot>) -> (usize, usize) {
+    let size = st.boot_services().memory_map_size();
+    let entries = size.map_size / size.entry_size + MMAP_SLACK_ENTRIES;
 
-    (gop.frame_buffer(), mode)
+    (entries * size.entry_size, entries)
 }
 
 #[entry]
 fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     x86_64::instructions::interrupts::disable();
-    uefi_services::init(&mut system_table).expect("Failed to setup UEFI services");
+    unsafe {
+        uefi::alloc::init(system_table.boot_services());
+        SYSTEM_TABLE = Some(system_table.unsafe_clone());
+    }
     system_table
         .stdout()
         .reset(false)
         .expect("Failed to reset stdout");
+    boot_log::init(&system_table);
 
     info!("Tyto x86_64 UEFI bootloader v{}", env!("CARGO_PKG_VERSION"));
     let rev = system_table.uefi_revision();
@@ -236,23 +332,87 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     let (pml4_frame, cr3_flags) = Cr3::read();
     info!("PML4 -> {:#x}", pml4_frame.start_address().as_u64());
 
+    // The kernel is mapped W^X, which relies on the NX bit being honoured
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+
+    let config = config::BootConfig::load(handle, &system_table);
+    info!("{:?}", config);
+
+    // Everything that is booted is checked before any of it is parsed
+    let manifest = match verify::Manifest::load(handle, &system_table) {
+        Ok(manifest) => manifest,
+        Err(e) => return verify::refuse(&system_table, &e),
+    };
+
+    // EFI applications that exit come back to the menu
+    let mut countdown = true;
+    let entry = loop {
+        let entry = menu::choose(&system_table, &config, countdown);
+        match entry.kind {
+            config::EntryKind::Tyto => break entry,
+            config::EntryKind::Efi => chainload::run(handle, &system_table, &entry, &manifest),
+        }
+        countdown = false;
+    };
+
+    info!("Loading kernel from {}", entry.kernel);
+
+    let kernel_image =
+        fs::read_file(handle, &system_table, &entry.kernel).expect("Kernel image not found");
+    let kernel_digest = match manifest.check(&entry.kernel, &kernel_image) {
+        Ok(digest) => digest,
+        Err(e) => return verify::refuse(&system_table, &e),
+    };
+
+    info!("Kernel {}", kernel_digest);
+
+    let modules = loader::load_modules(handle, &system_table, &entry.modules);
+
+    for module in modules.iter() {
+        if let Err(e) = manifest.check(&module.path, module.data()) {
+            return verify::refuse(&system_table, &e);
+        }
+    }
+
     info!("Initializing framebuffer");
 
-    let (mut framebuffer, framebuffer_mode) = init_framebuffer(&mut system_table);
+    let mut framebuffer = init_framebuffer(&mut system_table, config.resolution);
+
+    if framebuffer.is_none() {
+        warn!("Booting headless, the kernel will log to the serial port only");
+    }
 
     info!("Loading memory map");
 
-    let mmap_size = system_table.boot_services().memory_map_size().map_size + 0x2000;
-    let mut mmap_buf = vec![0; mmap_size];
+    let mut mmap_buf = vec![0; memory_map_capacity(&system_table).0];
 
     let (_, mmap_it) = system_table
         .boot_services()
         .memory_map(&mut mmap_buf)
         .expect("Failed to get memory map");
 
-    let mmap = ArrayVec::<_, 512>::from_iter(mmap_it.map(Clone::clone));
+    // The map is not sorted, and the framebuffer may lie above all of RAM
+    let phys_end = mmap_it
+        .map(|d| d.phys_start + d.page_count * Size4KiB::SIZE)
+        .chain(
+            framebuffer
+                .as_mut()
+                .map(|(fb, _)| fb.as_mut_ptr() as u64 + fb.size() as u64),
+        )
+        .max()
+        .unwrap();
+
+    let kaslr = kaslr::Kaslr::new(&system_table, config.kaslr);
+
+    let phys_map_size = align_up(phys_end, Size4KiB::SIZE);
+    let phys_map_offset = kaslr.choose(
+        KASLR_PHYS_MAP_RANGE,
+        phys_map_size,
+        KASLR_PHYS_MAP_ALIGN,
+        DEFAULT_PHYS_MAP_OFFSET,
+    );
 
-    info!("Mapping physical memory at offset {:#x}", PHYS_MAP_OFFSET);
+    info!("Mapping physical memory at offset {:#x}", phys_map_offset);
 
     let new_pml4 = system_table
         .boot_services()
@@ -282,11 +442,9 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     unsafe {
         map_offset(
-            VirtAddr::new(PHYS_MAP_OFFSET as _),
+            VirtAddr::new(phys_map_offset),
             PhysAddr::new(0),
-            mmap.last()
-                .map(|d| d.phys_start / Size4KiB::SIZE + d.page_count)
-                .unwrap(),
+            phys_map_size / Size4KiB::SIZE,
             &mut page_table,
             &mut UefiAlloc {},
             PageTableFlags::empty()
@@ -303,29 +461,32 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     let mut page_table = unsafe {
         OffsetPageTable::new(
             &mut *(Cr3::read().0.start_address().as_u64() as *mut PageTable),
-            VirtAddr::new(PHYS_MAP_OFFSET as _),
+            VirtAddr::new(phys_map_offset),
         )
     };
 
-    info!("Setting virtual address map");
-
-    let (_, mmap_it) = system_table
-        .boot_services()
-        .memory_map(&mut mmap_buf)
-        .expect("Failed to get memory map");
-
-    let mut mmap = ArrayVec::<_, 512>::from_iter(mmap_it.map(Clone::clone));
+    let kernel =
+        unsafe { loader::load_kernel(&kernel_image, &system_table, &mut page_table, &kaslr) };
 
-    info!("Loading kernel");
+    // The guard pages are part of the range so nothing else ends up there
+    let stack_size = KERNEL_STACK_SIZE_PAGES * Size4KiB::SIZE;
+    let stack_guard_size = KERNEL_STACK_GUARD_PAGES * Size4KiB::SIZE;
+    let stack_bottom = kaslr.choose(
+        KASLR_STACK_RANGE,
+        stack_guard_size + stack_size,
+        KASLR_STACK_ALIGN,
+        DEFAULT_KERNEL_STACK_BOTTOM - stack_size - stack_guard_size,
+    ) + stack_guard_size
+        + stack_size;
 
     info!(
         "Allocating kernel stack and mapping it at {:#x}",
-        KERNEL_STACK_BOTTOM
+        stack_bottom
     );
 
     unsafe {
         map_stack(
-            VirtAddr::new(KERNEL_STACK_BOTTOM),
+            VirtAddr::new(stack_bottom),
             KERNEL_STACK_SIZE_PAGES,
             &mut system_table,
             &mut page_table,
@@ -334,22 +495,77 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
     info!("Initializing kernel args struct");
 
-    let args = unsafe {
-        &mut *((system_table
-            .boot_services()
-            .allocate_pages(
-                AllocateType::AnyPages,
-                MemoryType::custom(KERNEL_ARGS_MEM_TYPE),
-                (align_up(size_of::<KernelArgs>() as u64, Size4KiB::SIZE) / Size4KiB::SIZE)
-                    as usize,
-            )
-            .expect("Could not allocate kernel args")
-            + PHYS_MAP_OFFSET) as usize as *mut MaybeUninit<KernelArgs>)
-    };
+    let (_, mmap_entries) = memory_map_capacity(&system_table);
+    let args_size = size_of::<KernelArgs>()
+        + KernelArgsBuilder::tag_size(size_of::<FramebufferInfo>())
+        + KernelArgsBuilder::tag_size(size_of::<UefiRuntimeInfo>())
+        + KernelArgsBuilder::tag_size(size_of::<AcpiRsdpInfo>())
+        + KernelArgsBuilder::tag_size(size_of::<SmbiosInfo>())
+        + KernelArgsBuilder::tag_size(size_of::<LayoutInfo>())
+        + KernelArgsBuilder::tag_size(size_of::<KernelDigestInfo>())
+        + KernelArgsBuilder::tag_size(entry.cmdline.len())
+        + modules
+            .iter()
+            .map(|m| KernelArgsBuilder::tag_size(size_of::<ModuleInfo>() + m.name.len()))
+            .sum::<usize>()
+        + KernelArgsBuilder::tag_size_with_entries::<MemoryMapInfo, MemoryDescriptor>(mmap_entries)
+        + KernelArgsBuilder::tag_size(boot_log::BOOT_LOG_CAPACITY)
+        + KernelArgsBuilder::tag_size(0);
+
+    let args_mem = system_table
+        .boot_services()
+        .allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::custom(KERNEL_ARGS_MEM_TYPE),
+            (align_up(args_size as u64, Size4KiB::SIZE) / Size4KiB::SIZE) as usize,
+        )
+        .expect("Could not allocate kernel args");
+
+    let mut args =
+        unsafe { KernelArgsBuilder::new((args_mem + phys_map_offset) as *mut u8, args_size) };
+
+    args.add(
+        TagKind::LAYOUT,
+        &LayoutInfo {
+            phys_map_offset,
+            phys_map_size,
+            stack_bottom,
+            stack_size,
+            stack_guard_size,
+            kernel_base: kernel.base.as_u64(),
+            kernel_size: kernel.size,
+            kernel_slide: kernel.slide,
+            randomized: kernel.randomized as u32,
+        },
+    );
+
+    args.add(TagKind::KERNEL_DIGEST, &kernel_digest);
+
+    if let Some((framebuffer, mode)) = framebuffer.as_mut() {
+        args.add(
+            TagKind::FRAMEBUFFER,
+            &framebuffer_info(framebuffer, mode, phys_map_offset),
+        );
+    }
 
-    let args_ptr = args.as_mut_ptr();
+    if let Some(rsdp) = platform::find_acpi_rsdp(&system_table) {
+        args.add(TagKind::ACPI_RSDP, &rsdp);
+    }
+
+    if let Some(smbios) = platform::find_smbios(&system_table) {
+        args.add(TagKind::SMBIOS, &smbios);
+    }
+
+    args.add_bytes(TagKind::COMMAND_LINE, entry.cmdline.as_bytes());
+
+    for module in modules.iter() {
+        args.add_with_bytes(TagKind::MODULE, &module.info, module.name.as_bytes());
+    }
 
-    match page_table.translate(VirtAddr::new(kernel_main as usize as u64)) {
+    // Sized last so that it covers the allocations made above
+    let mut mmap_buf = vec![0; memory_map_capacity(&system_table).0];
+
+    match page_table.translate(kernel.entry) {
         TranslateResult::Mapped { flags, .. } => unsafe {
             if flags.contains(PageTableFlags::NO_EXECUTE) {
                 panic!("Kernel entry point non-executable {:?}", flags)
@@ -357,59 +573,83 @@ fn efi_main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
 
             info!("Exiting boot services and calling kernel entry point");
 
-            let (mut uefi_rst, _) = system_table
+            boot_log::exit_boot_services();
+            uefi::alloc::exit_boot_services();
+            SYSTEM_TABLE = None;
+
+            let (mut uefi_rst, mmap_it) = system_table
                 .exit_boot_services(handle, &mut mmap_buf)
                 .expect("Failed to exit UEFI boot services");
 
+            info!("Exited boot services");
+
+            // Read-only kernel segments must be enforced in ring 0 as well
+            Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+
+            // The final map goes straight into the kernel args, where it is
+            // also updated by the firmware
+            let mmap = args.add_with_entries(
+                TagKind::MEMORY_MAP,
+                &MemoryMapInfo {
+                    entry_size: size_of::<MemoryDescriptor>() as u32,
+                    entry_count: mmap_it.len() as u32,
+                },
+                mmap_it.copied(),
+            );
+
             mmap.iter_mut()
-                .for_each(|x| x.virt_start = x.phys_start + PHYS_MAP_OFFSET);
+                .for_each(|x| x.virt_start = x.phys_start + phys_map_offset);
 
             let current_system_table_addr = uefi_rst.get_current_system_table_addr();
 
             uefi_rst = uefi_rst
-                .set_virtual_address_map(
-                    mmap.as_mut_slice(),
-                    current_system_table_addr + PHYS_MAP_OFFSET,
-                )
+                .set_virtual_address_map(mmap, current_system_table_addr + phys_map_offset)
                 .expect("Setting UEFI memory map failed");
 
-            addr_of_mut!((*args_ptr).uefi_rst).write(uefi_rst);
-            addr_of_mut!((*args_ptr).mmap).write(mmap);
-            addr_of_mut!((*args_ptr).framebuffer_addr)
-                .write((framebuffer.as_mut_ptr() as u64 + PHYS_MAP_OFFSET) as _);
-            addr_of_mut!((*args_ptr).framebuffer_info).write(framebuffer_mode);
-
-            let args_ptr = args.assume_init_mut() as *mut KernelArgs;
+            args.add(
+                TagKind::UEFI_RUNTIME,
+                &UefiRuntimeInfo {
+                    system_table: uefi_rst.get_current_system_table_addr(),
+                },
+            );
+            info!("Jumping to the kernel at {:?}", kernel.entry);
+            boot_log::with_contents(|log| args.add_bytes(TagKind::BOOT_LOG, log));
 
-            execute_kernel(kernel_main, args_ptr);
+            execute_kernel(kernel.entry, args.finish(), VirtAddr::new(stack_bottom));
         },
         e => panic!("Kernel entry point inaccessible: {:?}", e),
     }
 }
 
-fn execute_kernel(
-    // to ensure that the types are correct.
-    _kernel_main: fn(KernelArgs) -> !,
-    kernel_args: *mut KernelArgs,
-) -> ! {
-    // Switch the stack and call the entry point according to Microsoft x64
-    // calling convention
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("Bootloader panic: {}", info);
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("Out of memory while allocating {:?}", layout)
+}
+
+fn execute_kernel(entry: VirtAddr, kernel_args: *mut KernelArgs, stack: VirtAddr) -> ! {
+    // Switch the stack and jump to the entry point according to the System V
+    // calling convention. The pushed null return address keeps the stack
+    // aligned the way a `call` would and terminates stack traces.
 
     unsafe {
         asm!(
             "mov rsp, r8",
-            "mov rbp, r8",
+            "xor rbp, rbp",
+            "push rbp",
             "jmp rdx",
-            in("r8") KERNEL_STACK_BOTTOM,
-            in("rcx") kernel_args,
-            in("rdx") kernel_wrapper as *const u8,
+            in("r8") stack.as_u64(),
+            in("rdi") kernel_args,
+            in("rdx") entry.as_u64(),
+            options(noreturn)
         )
-    };
-
-    unreachable!()
-}
-
-// called by assembly
-fn kernel_wrapper(kernel_args: *mut KernelArgs) -> ! {
-    kernel_main(unsafe { kernel_args.read() });
+    }
 }
@@ -0,0 +1,260 @@
+use alloc::{borrow::ToOwned, format, string::String};
+use core::{fmt::Write, str};
+use log::{info, warn};
+use uefi::{
+    prelude::*,
+    proto::console::text::{Color, Key, Output, ScanCode},
+    table::runtime::{VariableAttributes, VariableVendor},
+    CString16, Guid,
+};
+
+use crate::config::{BootConfig, BootEntry};
+
+/// Used when the config has several entries but no `timeout`
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+const POLL_INTERVAL_US: u64 = 50_000;
+
+/// Holds the name of the entry last booted from the menu
+const LAST_ENTRY_VARIABLE: &str = "TytoLastEntry";
+
+fn vendor() -> VariableVendor {
+    VariableVendor(Guid::from_values(
+        0x6f747954,
+        0x6f62,
+        0x746f,
+        0x8d1a,
+        [0x6d, 0x65, 0x6e, 0x75, 0x00, 0x01],
+    ))
+}
+
+fn load_last_entry(st: &SystemTable<Boot>) -> Option<String> {
+    let name = CString16::try_from(LAST_ENTRY_VARIABLE).unwrap();
+    let mut buf = [0; 256];
+
+    let (size, _) = st
+        .runtime_services()
+        .get_variable(&name, &vendor(), &mut buf)
+        .ok()?;
+
+    str::from_utf8(&buf[..size]).ok().map(ToOwned::to_owned)
+}
+
+fn save_last_entry(st: &SystemTable<Boot>, entry: &str) {
+    let name = CString16::try_from(LAST_ENTRY_VARIABLE).unwrap();
+
+    if let Err(e) = st.runtime_services().set_variable(
+        &name,
+        &vendor(),
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS,
+        entry.as_bytes(),
+    ) {
+        warn!("Failed to remember the boot entry ({:?})", e.status());
+    }
+}
+
+/// The entry selected before the user presses anything: the configured
+/// default, else the one booted last time, else the first one
+fn initial_entry(st: &SystemTable<Boot>, config: &BootConfig) -> usize {
+    let position = |name: &str| config.entries.iter().position(|e| e.name == name);
+
+    match &config.default {
+        Some(default) => position(default).unwrap_or_else(|| {
+            warn!("Default entry {:?} does not exist", default);
+            0
+        }),
+        None => load_last_entry(st)
+            .and_then(|last| position(&last))
+            .unwrap_or(0),
+    }
+}
+
+/// Shows the boot menu on the text console and returns the entry to boot,
+/// with the command line as edited by the user. With a single entry and no
+/// `timeout`, or with `timeout = 0`, the menu is skipped. Without `countdown`
+/// the menu is always shown and waits for the user, which is what happens
+/// once a chainloaded application exits.
+pub(crate) fn choose(st: &SystemTable<Boot>, config: &BootConfig, countdown: bool) -> BootEntry {
+    let entries = &config.entries;
+    let mut selected = initial_entry(st, config);
+
+    let timeout = config.timeout.unwrap_or(if entries.len() > 1 {
+        DEFAULT_TIMEOUT_SECS
+    } else {
+        0
+    });
+
+    if countdown && timeout == 0 {
+        info!("Booting {}", entries[selected].name);
+        return entries[selected].clone();
+    }
+
+    let stdout = st.stdout();
+    let stdin = st.stdin();
+    stdin
+        .reset(false)
+        .expect("Failed to reset the console input");
+    let _ = stdout.enable_cursor(false);
+    let _ = stdout.clear();
+
+    // Cleared as soon as a key is pressed
+    let mut remaining_us = countdown.then(|| timeout * 1_000_000);
+    let mut redraw = true;
+
+    let entry = loop {
+        if redraw {
+            draw(stdout, config, selected, remaining_us);
+            redraw = false;
+        }
+
+        let key = match stdin.read_key().expect("Failed to read a key") {
+            Some(key) => key,
+            None => {
+                match remaining_us.as_mut() {
+                    Some(0) => break entries[selected].clone(),
+                    Some(remaining) => {
+                        let secs = ceil_secs(*remaining);
+                        *remaining = remaining.saturating_sub(POLL_INTERVAL_US);
+                        redraw = ceil_secs(*remaining) != secs;
+                    }
+                    None => {}
+                }
+
+                st.boot_services().stall(POLL_INTERVAL_US as _);
+                continue;
+            }
+        };
+
+        remaining_us = None;
+        redraw = true;
+
+        match key {
+            Key::Special(ScanCode::UP) => selected = (selected + entries.len() - 1) % entries.len(),
+            Key::Special(ScanCode::DOWN) => selected = (selected + 1) % entries.len(),
+            Key::Printable(c) if char::from(c) == '\r' => {
+                save_last_entry(st, &entries[selected].name);
+                break entries[selected].clone();
+            }
+            Key::Printable(c) if char::from(c) == 'e' => {
+                if let Some(cmdline) = edit(st, entries.len() + 5, &entries[selected].cmdline) {
+                    save_last_entry(st, &entries[selected].name);
+                    break BootEntry {
+                        cmdline,
+                        ..entries[selected].clone()
+                    };
+                }
+            }
+            _ => {}
+        }
+    };
+
+    let _ = stdout.set_color(Color::LightGray, Color::Black);
+    let _ = stdout.clear();
+    let _ = stdout.enable_cursor(true);
+
+    info!("Booting {}", entry.name);
+    entry
+}
+
+fn ceil_secs(us: u64) -> u64 {
+    (us + 999_999) / 1_000_000
+}
+
+fn columns(stdout: &mut Output) -> usize {
+    stdout
+        .current_mode()
+        .ok()
+        .flatten()
+        .map_or(80, |mode| mode.columns())
+}
+
+/// Writes `text` at the start of `row`, padded to clear what was there
+fn write_row(stdout: &mut Output, row: usize, text: &str, fg: Color, bg: Color) {
+    let width = columns(stdout) - 1;
+
+    // The menu is cosmetic, a console that cannot do this should not stop the boot
+    let _ = stdout.set_cursor_position(0, row);
+    let _ = stdout.set_color(fg, bg);
+    let _ = write!(stdout, "{:<width$}", text, width = width);
+}
+
+fn draw(stdout: &mut Output, config: &BootConfig, selected: usize, remaining_us: Option<u64>) {
+    let fg = Color::LightGray;
+    let bg = Color::Black;
+
+    write_row(stdout, 0, "Tyto boot menu", Color::White, bg);
+    write_row(stdout, 1, "", fg, bg);
+
+    for (i, entry) in config.entries.iter().enumerate() {
+        let text = format!("  {}", entry.name);
+        if i == selected {
+            write_row(stdout, i + 2, &text, Color::Black, Color::LightGray);
+        } else {
+            write_row(stdout, i + 2, &text, fg, bg);
+        }
+    }
+
+    let row = config.entries.len() + 2;
+    write_row(stdout, row, "", fg, bg);
+    write_row(
+        stdout,
+        row + 1,
+        "Up/Down to select, Enter to boot, e to edit the command line",
+        fg,
+        bg,
+    );
+
+    match remaining_us {
+        Some(us) => write_row(
+            stdout,
+            row + 2,
+            &format!("Booting in {} s", ceil_secs(us)),
+            fg,
+            bg,
+        ),
+        None => write_row(stdout, row + 2, "", fg, bg),
+    }
+}
+
+/// Lets the user edit `cmdline` on `row` for this boot only. Returns `None`
+/// if editing was cancelled with Escape.
+fn edit(st: &SystemTable<Boot>, row: usize, cmdline: &str) -> Option<String> {
+    let stdout = st.stdout();
+    let mut text = cmdline.to_owned();
+
+    loop {
+        write_row(
+            stdout,
+            row,
+            &format!("cmdline: {}_", text),
+            Color::White,
+            Color::Black,
+        );
+
+        match wait_for_key(st) {
+            Key::Special(ScanCode::ESCAPE) => {
+                write_row(stdout, row, "", Color::LightGray, Color::Black);
+                return None;
+            }
+            Key::Printable(c) => match char::from(c) {
+                '\r' => return Some(text),
+                '\u{8}' => {
+                    text.pop();
+                }
+                c if !c.is_control() => text.push(c),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Blocks until a key is pressed
+pub(crate) fn wait_for_key(st: &SystemTable<Boot>) -> Key {
+    loop {
+        if let Some(key) = st.stdin().read_key().expect("Failed to read a key") {
+            return key;
+        }
+
+        st.boot_services().stall(POLL_INTERVAL_US as _);
+    }
+}
@@ -0,0 +1,248 @@
+use alloc::{vec, vec::Vec};
+use core::ptr;
+use log::{info, warn};
+use uefi::{prelude::*, proto::Protocol, unsafe_guid};
+
+/// Paths starting with this are fetched over TFTP, `tftp://<server>/<file>`.
+/// Without a server, `tftp:///<file>`, the boot server named by DHCP is used.
+pub(crate) const TFTP_SCHEME: &str = "tftp://";
+
+const TFTP_GET_FILE_SIZE: u32 = 1;
+const TFTP_READ_FILE: u32 = 2;
+
+/// EFI_IP_ADDRESS, only IPv4 is used
+#[repr(C, align(4))]
+#[derive(Clone, Copy, Default)]
+struct IpAddress([u8; 16]);
+
+impl IpAddress {
+    fn v4(addr: [u8; 4]) -> Self {
+        let mut ip = Self::default();
+        ip.0[..4].copy_from_slice(&addr);
+        ip
+    }
+}
+
+/// EFI_PXE_BASE_CODE_PACKET
+#[repr(C, align(4))]
+struct Packet([u8; 1472]);
+
+impl Packet {
+    /// `siaddr` of a BOOTP/DHCPv4 packet, the server to boot from
+    fn next_server(&self) -> [u8; 4] {
+        [self.0[20], self.0[21], self.0[22], self.0[23]]
+    }
+}
+
+/// The start of EFI_PXE_BASE_CODE_MODE, up to the packets we read
+#[repr(C)]
+struct Mode {
+    started: bool,
+    ipv6_available: bool,
+    ipv6_supported: bool,
+    using_ipv6: bool,
+    bis_supported: bool,
+    bis_detected: bool,
+    auto_arp: bool,
+    send_guid: bool,
+    dhcp_discover_valid: bool,
+    dhcp_ack_received: bool,
+    proxy_offer_received: bool,
+    pxe_discover_valid: bool,
+    pxe_reply_received: bool,
+    pxe_bis_reply_received: bool,
+    icmp_error_received: bool,
+    tftp_error_received: bool,
+    make_callbacks: bool,
+    ttl: u8,
+    tos: u8,
+    station_ip: IpAddress,
+    subnet_mask: IpAddress,
+    dhcp_discover: Packet,
+    dhcp_ack: Packet,
+    proxy_offer: Packet,
+}
+
+/// The EFI_PXE_BASE_CODE_PROTOCOL, which uefi-rs does not provide. Functions
+/// the bootloader does not call are left untyped.
+#[repr(C)]
+#[unsafe_guid("03c4e603-ac28-11d3-9a2d-0090273fc14d")]
+#[derive(Protocol)]
+struct BaseCode {
+    revision: u64,
+    start: unsafe extern "efiapi" fn(this: &mut BaseCode, use_ipv6: bool) -> Status,
+    stop: usize,
+    dhcp: unsafe extern "efiapi" fn(this: &mut BaseCode, sort_offers: bool) -> Status,
+    discover: usize,
+    mtftp: unsafe extern "efiapi" fn(
+        this: &mut BaseCode,
+        operation: u32,
+        buffer: *mut u8,
+        overwrite: bool,
+        buffer_size: *mut u64,
+        block_size: *const usize,
+        server_ip: *const IpAddress,
+        filename: *const u8,
+        info: *const u8,
+        dont_use_buffer: bool,
+    ) -> Status,
+    udp_write: usize,
+    udp_read: usize,
+    set_ip_filter: usize,
+    arp: usize,
+    set_parameters: usize,
+    set_station_ip: usize,
+    set_packets: usize,
+    mode: *const Mode,
+}
+
+impl BaseCode {
+    fn mode(&self) -> &Mode {
+        unsafe { &*self.mode }
+    }
+
+    /// Starts the PXE stack and runs DHCP unless the firmware already did,
+    /// which it has if the bootloader itself came from the network
+    fn configure(&mut self) -> Option<()> {
+        if !self.mode().started {
+            let status = unsafe { (self.start)(self, false) };
+            if status.is_error() {
+                warn!("Failed to start PXE ({:?})", status);
+                return None;
+            }
+        }
+
+        if !self.mode().dhcp_ack_received {
+            info!("Requesting an address over DHCP");
+            let status = unsafe { (self.dhcp)(self, true) };
+            if status.is_error() {
+                warn!("DHCP failed ({:?})", status);
+                return None;
+            }
+        }
+
+        Some(())
+    }
+
+    fn mtftp(
+        &mut self,
+        operation: u32,
+        buffer: *mut u8,
+        size: &mut u64,
+        server: &IpAddress,
+        filename: &[u8],
+    ) -> Status {
+        unsafe {
+            (self.mtftp)(
+                self,
+                operation,
+                buffer,
+                false,
+                size,
+                ptr::null(),
+                server,
+                filename.as_ptr(),
+                ptr::null(),
+                false,
+            )
+        }
+    }
+}
+
+/// Splits a `tftp://` path into the server, if one is given, and the file name
+fn parse_url(url: &str) -> Option<(Option<[u8; 4]>, &str)> {
+    let (server, file) = url.strip_prefix(TFTP_SCHEME)?.split_once('/')?;
+
+    if server.is_empty() {
+        return Some((None, file));
+    }
+
+    let mut addr = [0; 4];
+    let mut octets = server.split('.');
+    for octet in addr.iter_mut() {
+        *octet = octets.next()?.parse().ok()?;
+    }
+
+    octets.next().is_none().then(|| (Some(addr), file))
+}
+
+pub(crate) fn is_network_path(path: &str) -> bool {
+    path.starts_with(TFTP_SCHEME)
+}
+
+/// Downloads a file over TFTP, returns `None` if it cannot be fetched
+pub(crate) fn read_file(st: &SystemTable<Boot>, url: &str) -> Option<Vec<u8>> {
+    let (server, file) = match parse_url(url) {
+        Some(parsed) => parsed,
+        None => {
+            warn!("Bad TFTP path {:?}, expected tftp://<server>/<file>", url);
+            return None;
+        }
+    };
+
+    let pxe = match st.boot_services().locate_protocol::<BaseCode>() {
+        Ok(pxe) => unsafe { &mut *pxe.get() },
+        Err(_) => {
+            warn!("No PXE capable network interface for {}", url);
+            return None;
+        }
+    };
+
+    pxe.configure()?;
+
+    let mode = pxe.mode();
+    let server = server.unwrap_or_else(|| mode.dhcp_ack.next_server());
+    if server == [0; 4] {
+        warn!("DHCP did not name a boot server for {}", url);
+        return None;
+    }
+
+    let station = mode.station_ip.0;
+    info!(
+        "Fetching {} from {}.{}.{}.{} as {}.{}.{}.{}",
+        file,
+        server[0],
+        server[1],
+        server[2],
+        server[3],
+        station[0],
+        station[1],
+        station[2],
+        station[3]
+    );
+
+    let server = IpAddress::v4(server);
+    let mut filename = file.as_bytes().to_vec();
+    filename.push(0);
+
+    let mut size = 0;
+    let status = pxe.mtftp(
+        TFTP_GET_FILE_SIZE,
+        ptr::null_mut(),
+        &mut size,
+        &server,
+        &filename,
+    );
+    if status.is_error() {
+        warn!("Failed to get the size of {} ({:?})", url, status);
+        return None;
+    }
+
+    let mut buf = vec![0; size as usize];
+    let status = pxe.mtftp(
+        TFTP_READ_FILE,
+        buf.as_mut_ptr(),
+        &mut size,
+        &server,
+        &filename,
+    );
+    if status.is_error() {
+        warn!("Failed to download {} ({:?})", url, status);
+        return None;
+    }
+
+    buf.truncate(size as usize);
+    info!("Downloaded {} ({} bytes)", url, buf.len());
+
+    Some(buf)
+}
@@ -0,0 +1,64 @@
+use boot_lib::{AcpiRsdpInfo, SmbiosInfo};
+use log::{info, warn};
+use uefi::{
+    prelude::*,
+    table::cfg::{ACPI2_GUID, ACPI_GUID, SMBIOS3_GUID, SMBIOS_GUID},
+    Guid,
+};
+
+fn find_table(st: &SystemTable<Boot>, guid: Guid) -> Option<u64> {
+    st.config_table()
+        .iter()
+        .find(|entry| entry.guid == guid)
+        .map(|entry| entry.address as u64)
+}
+
+/// Looks up the ACPI RSDP in the configuration table, preferring the ACPI 2.0 one
+pub(crate) fn find_acpi_rsdp(st: &SystemTable<Boot>) -> Option<AcpiRsdpInfo> {
+    let rsdp = find_table(st, ACPI2_GUID)
+        .map(|phys_addr| AcpiRsdpInfo {
+            phys_addr,
+            revision: 2,
+        })
+        .or_else(|| {
+            find_table(st, ACPI_GUID).map(|phys_addr| AcpiRsdpInfo {
+                phys_addr,
+                revision: 0,
+            })
+        });
+
+    match rsdp {
+        Some(rsdp) => info!(
+            "ACPI RSDP (revision {}) at {:#x}",
+            rsdp.revision, rsdp.phys_addr
+        ),
+        None => warn!("The firmware does not provide an ACPI RSDP"),
+    }
+
+    rsdp
+}
+
+/// Looks up the SMBIOS entry point, preferring the 64-bit SMBIOS 3.0 one
+pub(crate) fn find_smbios(st: &SystemTable<Boot>) -> Option<SmbiosInfo> {
+    let smbios = find_table(st, SMBIOS3_GUID)
+        .map(|phys_addr| SmbiosInfo {
+            phys_addr,
+            major_version: 3,
+        })
+        .or_else(|| {
+            find_table(st, SMBIOS_GUID).map(|phys_addr| SmbiosInfo {
+                phys_addr,
+                major_version: 2,
+            })
+        });
+
+    match smbios {
+        Some(smbios) => info!(
+            "SMBIOS {} entry point at {:#x}",
+            smbios.major_version, smbios.phys_addr
+        ),
+        None => warn!("The firmware does not provide SMBIOS tables"),
+    }
+
+    smbios
+}
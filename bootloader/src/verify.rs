@@ -0,0 +1,170 @@
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use boot_lib::{KernelDigestInfo, Verification};
+use core::{fmt, fmt::Write, str};
+use ed25519_compact::{PublicKey, Signature};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use uefi::{prelude::*, proto::console::text::Color};
+
+use crate::{fs, menu};
+
+/// Lines of `<sha256 in hex> <path>`, the format `sha256sum` writes
+pub(crate) const MANIFEST_PATH: &str = "\\EFI\\tyto\\manifest.sha256";
+/// The raw 64 byte Ed25519 signature of the manifest
+pub(crate) const SIGNATURE_PATH: &str = "\\EFI\\tyto\\manifest.sha256.sig";
+
+/// Hex encoded Ed25519 public key, set at build time. If present, the
+/// manifest must exist and be signed with the matching key.
+const VERIFY_KEY: Option<&str> = option_env!("TYTO_VERIFY_KEY");
+
+#[derive(Debug)]
+pub(crate) enum VerifyError {
+    BadKey,
+    MissingManifest,
+    MalformedManifest(usize),
+    MissingSignature,
+    BadSignature,
+    Unlisted(String),
+    Mismatch(String),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::BadKey => f.write_str("the built-in verification key is malformed"),
+            VerifyError::MissingManifest => write!(f, "{} is missing", MANIFEST_PATH),
+            VerifyError::MalformedManifest(line) => {
+                write!(f, "{} is malformed at line {}", MANIFEST_PATH, line)
+            }
+            VerifyError::MissingSignature => write!(f, "{} is missing", SIGNATURE_PATH),
+            VerifyError::BadSignature => {
+                write!(f, "the signature of {} does not match", MANIFEST_PATH)
+            }
+            VerifyError::Unlisted(path) => write!(f, "{} is not listed in the manifest", path),
+            VerifyError::Mismatch(path) => {
+                write!(f, "{} does not match its digest, it may be corrupt", path)
+            }
+        }
+    }
+}
+
+/// Digests of the files that may be booted
+pub(crate) struct Manifest {
+    digests: Vec<(String, [u8; 32])>,
+    verification: Verification,
+}
+
+impl Manifest {
+    /// Reads the manifest and checks its signature if a key was built in.
+    /// Without a manifest or a key nothing is checked.
+    pub(crate) fn load(image: Handle, st: &SystemTable<Boot>) -> Result<Self, VerifyError> {
+        let key = VERIFY_KEY
+            .map(|hex| {
+                parse_hex::<32>(hex)
+                    .and_then(|key| PublicKey::from_slice(&key).ok())
+                    .ok_or(VerifyError::BadKey)
+            })
+            .transpose()?;
+
+        let text = match (fs::read_file(image, st, MANIFEST_PATH), key) {
+            (Some(text), _) => text,
+            (None, Some(_)) => return Err(VerifyError::MissingManifest),
+            (None, None) => {
+                warn!("No {}, the kernel is not verified", MANIFEST_PATH);
+                return Ok(Self {
+                    digests: Vec::new(),
+                    verification: Verification::NONE,
+                });
+            }
+        };
+
+        let verification = match key {
+            Some(key) => {
+                let signature = fs::read_file(image, st, SIGNATURE_PATH)
+                    .ok_or(VerifyError::MissingSignature)?;
+                let signature =
+                    Signature::from_slice(&signature).map_err(|_| VerifyError::BadSignature)?;
+                key.verify(&text, &signature)
+                    .map_err(|_| VerifyError::BadSignature)?;
+
+                info!("{} is signed with the built-in key", MANIFEST_PATH);
+                Verification::SIGNED
+            }
+            None => Verification::MANIFEST,
+        };
+
+        let text = str::from_utf8(&text).map_err(|_| VerifyError::MalformedManifest(1))?;
+        let digests = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| {
+                // `sha256sum` puts a `*` in front of paths hashed in binary mode
+                line.split_once(char::is_whitespace)
+                    .and_then(|(digest, path)| {
+                        let path = path.trim().trim_start_matches('*');
+                        Some((path.to_owned(), parse_hex::<32>(digest)?))
+                    })
+                    .ok_or(VerifyError::MalformedManifest(number + 1))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            digests,
+            verification,
+        })
+    }
+
+    /// Hashes a file and checks it against the manifest. Paths are compared
+    /// ignoring case, like FAT does.
+    pub(crate) fn check(&self, path: &str, data: &[u8]) -> Result<KernelDigestInfo, VerifyError> {
+        let sha256: [u8; 32] = Sha256::digest(data).into();
+
+        if self.verification != Verification::NONE {
+            let expected = self
+                .digests
+                .iter()
+                .find(|(listed, _)| listed.eq_ignore_ascii_case(path))
+                .map(|(_, digest)| digest)
+                .ok_or_else(|| VerifyError::Unlisted(path.to_owned()))?;
+
+            if *expected != sha256 {
+                return Err(VerifyError::Mismatch(path.to_owned()));
+            }
+        }
+
+        Ok(KernelDigestInfo {
+            sha256,
+            verification: self.verification,
+        })
+    }
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+/// Tells the user why the boot was stopped, then hands control back to the
+/// firmware so it can try the next boot option
+pub(crate) fn refuse(st: &SystemTable<Boot>, reason: &VerifyError) -> Status {
+    let stdout = st.stdout();
+
+    let _ = stdout.set_color(Color::LightRed, Color::Black);
+    let _ = writeln!(stdout, "\nRefusing to boot: {}", reason);
+    let _ = stdout.set_color(Color::LightGray, Color::Black);
+    let _ = writeln!(stdout, "Press any key to return to the firmware");
+
+    menu::wait_for_key(st);
+
+    Status::SECURITY_VIOLATION
+}
@@ -0,0 +1,24 @@
+use std::{env, path::Path};
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let linker_script = Path::new(&manifest_dir).join("linker.ld");
+
+    println!("cargo:rerun-if-changed={}", linker_script.display());
+    println!("cargo:rustc-link-arg-bins=-T{}", linker_script.display());
+
+    // heap-debug walks the stack through rbp, which only works if every
+    // function keeps a frame pointer
+    if env::var_os("CARGO_FEATURE_HEAP_DEBUG").is_some() {
+        let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+        let flags: Vec<&str> = rustflags.split('\x1f').collect();
+        let forced = flags
+            .windows(2)
+            .any(|w| w == ["-C", "force-frame-pointers=yes"])
+            || flags.contains(&"-Cforce-frame-pointers=yes");
+
+        if !forced {
+            panic!("heap-debug needs -C force-frame-pointers=yes, build with `cargo kbuild-kernel-debug`");
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use boot_lib::{KernelArgs, LayoutInfo};
+use core::fmt::{Display, Write};
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::{data::LateInit, device::serial::SERIAL1};
+
+pub(crate) mod modules;
+mod options;
+pub(crate) use options::Options;
+
+static ARGS: LateInit<&'static KernelArgs> = LateInit::new();
+static OPTIONS: LateInit<Options> = LateInit::new();
+static LAYOUT: LateInit<&'static LayoutInfo> = LateInit::new();
+
+/// Validates the boot information and makes it available to the rest of the kernel
+pub(crate) fn init(args: &'static KernelArgs) {
+    if let Err(e) = args.validate() {
+        refuse(e);
+    }
+
+    let layout = match args.layout() {
+        Some(layout) => layout,
+        None => refuse("the bootloader did not report the memory layout"),
+    };
+
+    ARGS.init(|| args);
+    LAYOUT.init(|| layout);
+    OPTIONS.init(|| Options::parse(args.command_line().unwrap_or("")));
+}
+
+/// The boot information handed over by the bootloader
+pub(crate) fn args() -> &'static KernelArgs {
+    *ARGS
+}
+
+/// Where the kernel, its stack and the physical memory window were placed
+pub(crate) fn layout() -> &'static LayoutInfo {
+    *LAYOUT
+}
+
+/// Options from the kernel command line
+pub(crate) fn options() -> &'static Options {
+    &OPTIONS
+}
+
+fn refuse(reason: impl Display) -> ! {
+    // Nothing else is set up yet, so the serial port is the only way out
+    let _ = writeln!(SERIAL1.lock(), "Refusing to boot: {}", reason);
+
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
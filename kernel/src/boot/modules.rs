@@ -0,0 +1,36 @@
+use core::slice;
+use x86_64::PhysAddr;
+
+use super::args;
+use crate::mm;
+
+/// A file loaded by the bootloader, such as an initial ramdisk
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Module {
+    pub(crate) name: &'static str,
+    pub(crate) phys_addr: u64,
+    /// The contents, reached through the physical memory window
+    pub(crate) data: &'static [u8],
+}
+
+/// Every module handed over by the bootloader, in the order of the boot config
+pub(crate) fn modules() -> impl Iterator<Item = Module> {
+    args().modules().map(|(info, name)| Module {
+        name,
+        phys_addr: info.phys_addr,
+        data: if info.size == 0 {
+            &[]
+        } else {
+            unsafe {
+                slice::from_raw_parts(
+                    mm::phys_to_virt(PhysAddr::new(info.phys_addr)).as_ptr(),
+                    info.size as _,
+                )
+            }
+        },
+    })
+}
+
+pub(crate) fn find(name: &str) -> Option<Module> {
+    modules().find(|module| module.name == name)
+}
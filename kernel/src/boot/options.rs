@@ -0,0 +1,165 @@
+use log::{info, warn, LevelFilter};
+
+/// Kernel options parsed from the command line.
+///
+/// The command line is a whitespace separated list of `key=value` pairs and
+/// bare `key` flags, e.g. `log=trace timer_hz=100 ps2=off`. Later occurrences
+/// override earlier ones. Keys without a typed field are not an error, drivers
+/// can look them up with [`Options::get`].
+pub(crate) struct Options {
+    cmdline: &'static str,
+    /// `log=<off|error|warn|info|debug|trace>`
+    pub(crate) log_level: LevelFilter,
+    /// `timer_hz=<n>`, frequency of the PIT tick
+    pub(crate) timer_hz: u32,
+    /// `ps2=<on|off>`, whether to start the PS/2 keyboard driver
+    pub(crate) ps2: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            cmdline: "",
+            log_level: LevelFilter::Debug,
+            timer_hz: 200,
+            ps2: true,
+        }
+    }
+}
+
+impl Options {
+    pub(crate) fn parse(cmdline: &'static str) -> Self {
+        let mut options = Self {
+            cmdline,
+            ..Self::default()
+        };
+
+        for (key, value) in tokens(cmdline) {
+            // Bad values are reported by `log_summary` once logging works
+            let _ = options.apply(key, value);
+        }
+
+        options
+    }
+
+    fn apply(&mut self, key: &str, value: Option<&str>) -> Result<(), &'static str> {
+        match key {
+            "log" => {
+                self.log_level = value
+                    .ok_or("expected a level")?
+                    .parse()
+                    .map_err(|_| "unknown level")?
+            }
+            "timer_hz" => {
+                self.timer_hz = value
+                    .ok_or("expected a frequency")?
+                    .parse()
+                    .ok()
+                    // The PIT divisor is 16 bits wide
+                    .filter(|hz| (19..=1_193_182).contains(hz))
+                    .ok_or("expected a frequency between 19 and 1193182")?
+            }
+            "ps2" => self.ps2 = parse_bool(value)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Raw value of the last occurrence of `key`, an empty string for bare flags
+    pub(crate) fn get(&self, key: &str) -> Option<&'static str> {
+        tokens(self.cmdline)
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, value)| value.unwrap_or(""))
+    }
+
+    pub(crate) fn cmdline(&self) -> &'static str {
+        self.cmdline
+    }
+
+    /// Logs the command line and every option that was ignored
+    pub(crate) fn log_summary(&self) {
+        info!("Command line: {:?}", self.cmdline);
+
+        for (key, value) in tokens(self.cmdline) {
+            if let Err(reason) = Self::default().apply(key, value) {
+                warn!("Ignoring kernel option {:?}: {}", key, reason);
+            }
+        }
+    }
+}
+
+fn tokens(cmdline: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    cmdline
+        .split_whitespace()
+        .map(|token| match token.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (token, None),
+        })
+}
+
+fn parse_bool(value: Option<&str>) -> Result<bool, &'static str> {
+    match value {
+        None | Some("on" | "yes" | "true" | "1") => Ok(true),
+        Some("off" | "no" | "false" | "0") => Ok(false),
+        Some(_) => Err("expected on or off"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let options = Options::parse("");
+
+        assert_eq!(options.log_level, LevelFilter::Debug);
+        assert_eq!(options.timer_hz, 200);
+        assert!(options.ps2);
+    }
+
+    #[test]
+    fn typed_options() {
+        let options = Options::parse("log=trace  timer_hz=100\tps2=off");
+
+        assert_eq!(options.log_level, LevelFilter::Trace);
+        assert_eq!(options.timer_hz, 100);
+        assert!(!options.ps2);
+    }
+
+    #[test]
+    fn later_occurrences_win() {
+        let options = Options::parse("ps2=off ps2 log=info log=warn");
+
+        assert!(options.ps2);
+        assert_eq!(options.log_level, LevelFilter::Warn);
+    }
+
+    #[test]
+    fn bad_values_keep_the_default() {
+        let options = Options::parse("log=loud log timer_hz=18 ps2=maybe");
+
+        assert_eq!(options.log_level, LevelFilter::Debug);
+        assert_eq!(options.timer_hz, 200);
+        assert!(options.ps2);
+    }
+
+    #[test]
+    fn timer_hz_bounds() {
+        assert_eq!(Options::parse("timer_hz=19").timer_hz, 19);
+        assert_eq!(Options::parse("timer_hz=1193182").timer_hz, 1_193_182);
+        assert_eq!(Options::parse("timer_hz=1193183").timer_hz, 200);
+        assert_eq!(Options::parse("timer_hz=").timer_hz, 200);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let options = Options::parse("nosmp root=/dev/a=b root=/dev/c");
+
+        assert_eq!(options.get("nosmp"), Some(""));
+        assert_eq!(options.get("root"), Some("/dev/c"));
+        assert_eq!(options.get("init"), None);
+    }
+}
@@ -13,6 +13,10 @@ impl<T> LateInit<T> {
         self.0.call_once(init);
     }
 
+    pub(crate) fn is_initialized(&self) -> bool {
+        self.0.is_completed()
+    }
+
     pub(crate) fn into_inner(self) -> Once<T> {
         self.0
     }
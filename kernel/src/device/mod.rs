@@ -1,7 +1,11 @@
 pub(crate) mod ps2;
 pub(crate) mod serial;
 
+use crate::boot;
+
 pub(crate) fn init() {
-    ps2::init();
+    if boot::options().ps2 {
+        ps2::init();
+    }
     serial::init();
 }
@@ -0,0 +1,29 @@
+use futures_util::stream::StreamExt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+
+use crate::{device::ps2::keyboard::ScancodeStream, mm};
+
+/// Dumps kernel state when debug keys are pressed: F12 prints the heap report
+pub(crate) async fn run() {
+    let mut scancodes = ScancodeStream;
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+
+    while let Some(scancode) = scancodes.next().await {
+        let event = match keyboard.add_byte(scancode) {
+            Ok(Some(event)) => event,
+            _ => continue,
+        };
+
+        if let Some(DecodedKey::RawKey(KeyCode::F12)) = keyboard.process_keyevent(event) {
+            heap_report();
+        }
+    }
+}
+
+/// Live allocations by site with heap debugging, the heap statistics otherwise
+fn heap_report() {
+    #[cfg(feature = "heap-debug")]
+    mm::heap_debug::report_leaks();
+    #[cfg(not(feature = "heap-debug"))]
+    mm::heap::log_summary();
+}
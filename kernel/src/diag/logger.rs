@@ -1,15 +1,72 @@
 use core::{fmt, fmt::Write};
 
 use embedded_graphics_core::pixelcolor::{Rgb888, RgbColor, WebColors};
-use log::{Level, LevelFilter, Log, Metadata, Record};
+use log::{Level, Log, Metadata, Record};
 
 use crate::{
-    data::CrateMutex, device::serial::SERIAL1, graphics::framebuffer_term::FramebufferTextRender,
+    boot,
+    data::{CrateMutex, IRQLock},
+    device::serial::SERIAL1,
+    graphics::{framebuffer, framebuffer_term::FramebufferTextRender},
 };
 
 pub(crate) static GLOBAL_LOGGER: CrateMutex<DefaultLogger> =
     CrateMutex::new(DefaultLogger::new(None));
 
+/// Every log line, starting with the bootloader's
+pub(crate) static LOG_BUFFER: IRQLock<LogBuffer> = IRQLock::new(LogBuffer::new());
+
+const LOG_BUFFER_SIZE: usize = 128 * 1024;
+
+/// A ring buffer of log text, the oldest bytes are dropped when it is full
+pub(crate) struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; LOG_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.data[(self.start + self.len) % LOG_BUFFER_SIZE] = byte;
+            if self.len == LOG_BUFFER_SIZE {
+                self.start = (self.start + 1) % LOG_BUFFER_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+
+    /// The buffered text, oldest first. It may start in the middle of a
+    /// line, or of a character, once the buffer has wrapped.
+    pub(crate) fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.start + self.len;
+        if end <= LOG_BUFFER_SIZE {
+            (&self.data[self.start..end], &[])
+        } else {
+            (
+                &self.data[self.start..],
+                &self.data[..end - LOG_BUFFER_SIZE],
+            )
+        }
+    }
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
 pub(crate) struct DefaultLogger {
     term: Option<FramebufferTextRender>,
 }
@@ -32,6 +89,14 @@ impl Log for CrateMutex<DefaultLogger> {
                 .expect("Could not write log message to serial port");
         }
 
+        if !LOG_BUFFER.is_locked() {
+            let _ = LOG_BUFFER.lock().write_fmt(format_args!(
+                "[{}] {}\n",
+                record.level().as_str().chars().next().unwrap(),
+                record.args()
+            ));
+        }
+
         if self.is_locked() {
             return;
         }
@@ -83,12 +148,18 @@ impl DefaultLogger {
 }
 
 pub(crate) fn init() {
-    GLOBAL_LOGGER
-        .lock()
-        .reinit_with_framebuffer_term(FramebufferTextRender::new(Rgb888::BLACK));
+    if let Some(boot_log) = boot::args().boot_log() {
+        LOG_BUFFER.lock().push(boot_log.as_bytes());
+    }
+
+    if framebuffer::is_available() {
+        GLOBAL_LOGGER
+            .lock()
+            .reinit_with_framebuffer_term(FramebufferTextRender::new(Rgb888::BLACK));
+    }
 
     if log::set_logger(&GLOBAL_LOGGER).is_ok() {
-        log::set_max_level(LevelFilter::Debug)
+        log::set_max_level(boot::options().log_level)
     }
 }
 
@@ -0,0 +1,14 @@
+use core::panic::PanicInfo;
+
+use log::error;
+use x86_64::instructions::{hlt, interrupts};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    error!("Kernel panic: {}", info);
+
+    loop {
+        hlt();
+    }
+}
@@ -1,30 +1,44 @@
 use alloc::{vec, vec::Vec};
-use boot_lib::KernelArgs;
+use boot_lib::{FramebufferInfo, PixelFormat};
 use core::ptr::NonNull;
 
-use embedded_graphics_core::{pixelcolor::Rgb888, prelude::IntoStorage};
-use uefi::proto::console::gop::ModeInfo;
+use embedded_graphics_core::{
+    pixelcolor::{Rgb888, RgbColor},
+    prelude::IntoStorage,
+};
 
-use crate::data::{IRQLock, LateInit};
+use x86_64::VirtAddr;
+
+use crate::{
+    boot,
+    data::{IRQLock, LateInit},
+    mm::{
+        self,
+        io::{self, CacheMode, IoMapping},
+    },
+};
 
 pub(crate) static GLOBAL_FRAMEBUFFER: IRQLock<LateInit<FramebufferDisplay>> =
     IRQLock::new(LateInit::new());
 
 pub(crate) struct FramebufferDisplay {
-    pub(crate) mode: ModeInfo,
+    pub(crate) mode: FramebufferInfo,
     pub(crate) buffer: Vec<u32>,
     pub(crate) base: NonNull<u32>,
     pub(crate) size: u64,
+    /// The write-combining mapping `base` points into
+    mapping: IoMapping,
 }
 
 impl FramebufferDisplay {
-    pub(crate) fn new(base: NonNull<u32>, mode: ModeInfo) -> Self {
+    pub(crate) fn new(mapping: IoMapping, mode: FramebufferInfo) -> Self {
         let size = mode.resolution().1 * mode.stride();
         Self {
             size: size as u64,
-            base,
+            base: NonNull::new(mapping.as_mut_ptr()).unwrap(),
             buffer: vec![0; size],
             mode,
+            mapping,
         }
     }
 
@@ -39,24 +53,69 @@ impl FramebufferDisplay {
     pub(crate) fn scroll_up(&mut self, height: usize, bg: Rgb888) {
         let high = self.mode.stride() * height;
         let low = self.mode.stride() * self.mode.resolution().1;
-        self.buffer[0..(high - 1)].fill(bg.into_storage());
+        let bg = self.encode(bg);
+        self.buffer[0..(high - 1)].fill(bg);
         self.buffer.copy_within(high..low, 0)
     }
 
     pub(crate) fn fill(&mut self, color: Rgb888) {
-        self.buffer.fill(color.into_storage());
+        let color = self.encode(color);
+        self.buffer.fill(color);
     }
 
     pub(crate) fn write(&mut self, pos: usize, color: Rgb888) {
-        self.buffer[pos] = color.into_storage();
+        self.buffer[pos] = self.encode(color);
+    }
+
+    /// Converts a color to the pixel layout of the framebuffer
+    fn encode(&self, color: Rgb888) -> u32 {
+        match self.mode.format {
+            PixelFormat::RGB => {
+                u32::from(color.r()) | u32::from(color.g()) << 8 | u32::from(color.b()) << 16
+            }
+            PixelFormat::BGR => color.into_storage(),
+            _ => {
+                encode_channel(color.r(), self.mode.red_mask)
+                    | encode_channel(color.g(), self.mode.green_mask)
+                    | encode_channel(color.b(), self.mode.blue_mask)
+            }
+        }
+    }
+}
+
+/// Scales an 8 bit channel to the width of `mask` and moves it into place
+fn encode_channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
     }
+
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    ((u32::from(value) * max / 0xFF) << shift) & mask
+}
+
+/// Sets up the framebuffer if the bootloader provided one, the kernel runs
+/// headless otherwise
+pub(crate) fn init() {
+    let info = match boot::args().framebuffer() {
+        Some(info) => *info,
+        None => return,
+    };
+
+    // Writes through the write-back physical memory window are much slower
+    let mapping = io::ioremap(
+        "framebuffer",
+        mm::virt_to_phys(VirtAddr::new(info.address)),
+        info.size,
+        CacheMode::WriteCombining,
+    )
+    .expect("Could not map the framebuffer");
+
+    GLOBAL_FRAMEBUFFER
+        .lock()
+        .init(|| FramebufferDisplay::new(mapping, info));
 }
 
-pub(crate) fn init(args: &mut KernelArgs) {
-    GLOBAL_FRAMEBUFFER.lock().init(|| {
-        FramebufferDisplay::new(
-            NonNull::new(args.framebuffer_addr as *mut u32).unwrap(),
-            args.framebuffer_info,
-        )
-    });
+pub(crate) fn is_available() -> bool {
+    GLOBAL_FRAMEBUFFER.lock().is_initialized()
 }
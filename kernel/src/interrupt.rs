@@ -1,5 +1,6 @@
 use core::{
     arch::asm,
+    ptr::addr_of,
     sync::atomic::{AtomicU64, Ordering},
 };
 
@@ -7,19 +8,23 @@ use log::{error, info};
 use pic8259::ChainedPics;
 use spin::Lazy;
 use x86_64::{
-    instructions::port::Port,
+    instructions::{
+        port::Port,
+        segmentation::{Segment, CS, DS, ES, FS, GS, SS},
+        tables::load_tss,
+    },
     registers::control::Cr2,
     set_general_handler,
     structures::{
-        gdt::{Descriptor, GlobalDescriptorTable},
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
-        paging::{PageSize, Size2MiB},
+        paging::{PageSize, Size2MiB, Size4KiB},
         tss::TaskStateSegment,
     },
     VirtAddr,
 };
 
-use crate::data::IRQLock;
+use crate::{boot, data::IRQLock, mm, mm::demand::DemandError};
 
 pub(crate) const PIC_OFFSET: u8 = 32;
 
@@ -38,35 +43,61 @@ impl IntIdx {
 
 static TIMER_VAL: AtomicU64 = AtomicU64::new(0);
 
-pub(crate) static DOUBLE_FAULT_STACK: [u8; Size2MiB::SIZE as usize] = [0; Size2MiB::SIZE as usize];
+const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// A stack overflow faults while pushing the exception frame, so the handler
+// needs a stack of its own. A fault inside the handler clobbers it, which is
+// fine as long as the handler does not return from such a fault.
+const PAGE_FAULT_IST_INDEX: u16 = 1;
+const PAGE_FAULT_STACK_SIZE: usize = 16 * Size4KiB::SIZE as usize;
+
+// Must be mutable, immutable statics end up in a read-only segment
+static mut DOUBLE_FAULT_STACK: [u8; Size2MiB::SIZE as usize] = [0; Size2MiB::SIZE as usize];
+static mut PAGE_FAULT_STACK: [u8; PAGE_FAULT_STACK_SIZE] = [0; PAGE_FAULT_STACK_SIZE];
 
 static TSS: Lazy<IRQLock<TaskStateSegment>> = Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
 
-    tss.interrupt_stack_table[1] = VirtAddr::new(&DOUBLE_FAULT_STACK as *const _ as u64);
+    // Stacks grow down, so the IST holds the end of each stack
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        unsafe { VirtAddr::from_ptr(addr_of!(DOUBLE_FAULT_STACK)) + Size2MiB::SIZE };
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+        unsafe { VirtAddr::from_ptr(addr_of!(PAGE_FAULT_STACK)) + PAGE_FAULT_STACK_SIZE };
 
     IRQLock::new(tss)
 });
-static GDT: Lazy<GlobalDescriptorTable> = Lazy::new(|| {
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
 
-    gdt.add_entry(Descriptor::kernel_code_segment());
-    gdt.add_entry(Descriptor::kernel_data_segment());
+    let code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data = gdt.add_entry(Descriptor::kernel_data_segment());
     gdt.add_entry(Descriptor::user_code_segment());
     gdt.add_entry(Descriptor::user_data_segment());
-    gdt.add_entry(Descriptor::tss_segment(unsafe {
+    let tss = gdt.add_entry(Descriptor::tss_segment(unsafe {
         &*(&*TSS.lock() as *const _)
     }));
 
-    gdt
+    (gdt, Selectors { code, data, tss })
 });
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
 
     set_general_handler!(&mut idt, general_handler);
 
-    idt.page_fault.set_handler_fn(page_fault);
-    idt.double_fault.set_handler_fn(double_fault);
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(page_fault)
+            .set_stack_index(PAGE_FAULT_IST_INDEX);
+        idt.double_fault
+            .set_handler_fn(double_fault)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
     idt.breakpoint.set_handler_fn(breakpoint);
     idt.general_protection_fault
         .set_handler_fn(general_protection_fault);
@@ -79,52 +110,77 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
 pub(crate) static PICS: IRQLock<ChainedPics> =
     IRQLock::new(unsafe { ChainedPics::new(PIC_OFFSET, PIC_OFFSET + 8) });
 
-/// Loads the GDT and fixes the segments
+/// Loads the GDT, reloads the segment registers and loads the TSS
 fn load_gdt() {
-    // FIXME
-    GDT.load();
+    let (gdt, selectors) = &*GDT;
+    gdt.load();
 
     unsafe {
-        asm!(
-            "push 0x08",
-            "mov AX, 0x10",
-            "mov DS, AX",
-            "mov ES, AX",
-            "mov FS, AX",
-            "mov GS, AX",
-            "mov SS, AX"
-        )
+        CS::set_reg(selectors.code);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        FS::set_reg(selectors.data);
+        GS::set_reg(selectors.data);
+        SS::set_reg(selectors.data);
+        load_tss(selectors.tss);
     }
 }
 
-const PIC_TERM_COUNT: u16 = 5966; // Should fire roughly each 5 ms
+const PIT_FREQUENCY: u32 = 1_193_182;
 
-/// Initialize GDT, IDT and PIC
-pub(crate) fn init() {
+/// Loads the GDT and the IDT, so that exceptions are handled before anything
+/// else is set up
+pub(crate) fn init_exceptions() {
     load_gdt();
     IDT.load();
+}
+
+/// Initializes the PIC, unmasking the timer and keyboard lines, and starts the
+/// PIT at `timer_hz`
+pub(crate) fn init() {
     unsafe {
         let mut pic = PICS.lock();
         pic.initialize();
-        pic.write_masks(!3, !3);
+        // Timer, and the keyboard unless the PS/2 driver is disabled
+        let mask = if boot::options().ps2 { !3 } else { !1 };
+        pic.write_masks(mask, !3);
     }
     // FIXME
     // Configure the PIC
 
+    let divisor = (PIT_FREQUENCY / boot::options().timer_hz) as u16;
+
     let mut port1 = Port::new(0x43);
     let mut port2 = Port::new(0x40);
     unsafe {
         port1.write(0b00110100u8);
-        port2.write((PIC_TERM_COUNT & 0xff) as u8);
-        port2.write((PIC_TERM_COUNT >> 8) as u8);
+        port2.write((divisor & 0xff) as u8);
+        port2.write((divisor >> 8) as u8);
     }
 }
 
 extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, code: PageFaultErrorCode) {
+    let addr = Cr2::read();
+
+    if mm::stack::is_guard_hit(addr) {
+        error!(
+            "Kernel stack overflow at RIP {:#x}, CR2: {:#x}",
+            frame.instruction_pointer.as_u64(),
+            addr.as_u64()
+        );
+        panic!("kernel stack overflow")
+    }
+
+    match mm::demand::handle_fault(addr, code) {
+        Ok(()) => return,
+        Err(DemandError::NotLazy) => {}
+        Err(e) => error!("Could not back the page at {:#x}: {}", addr.as_u64(), e),
+    }
+
     error!("Page fault occured");
     error!("{:#?}", frame);
     error!("Code: {:?}", code);
-    error!("CR2: {:#x}", Cr2::read().as_u64());
+    error!("CR2: {:#x}", addr.as_u64());
     panic!("Page Fault!")
 }
 
@@ -147,12 +203,14 @@ extern "x86-interrupt" fn general_protection_fault(frame: InterruptStackFrame, f
     panic!("GPF");
 }
 
-/// Timer fires each 5 seconds
+/// Timer fires `timer_hz` times a second
 extern "x86-interrupt" fn timer(_frame: InterruptStackFrame) {
+    let hz = boot::options().timer_hz as u64;
     let old = TIMER_VAL.fetch_add(1, Ordering::SeqCst);
-    if (old + 1) % 200 == 0 {
-        info!("TIMER SECOND {}", (old + 1) / 200);
+    if (old + 1) % hz == 0 {
+        info!("TIMER SECOND {}", (old + 1) / hz);
     }
+    mm::stack::watch_canary();
     unsafe {
         PICS.lock().notify_end_of_interrupt(IntIdx::Timer.as_u8());
     }
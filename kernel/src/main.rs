@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
+#![feature(nonnull_slice_from_raw_parts)]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+use boot_lib::KernelArgs;
+use log::{info, warn};
+use spin::{Lazy, Mutex};
+use task::{executor::Executor, Task};
+
+pub(crate) static EXECUTOR: Lazy<Mutex<Executor>> = Lazy::new(|| Mutex::new(Executor::new()));
+
+pub(crate) mod boot;
+pub(crate) mod data;
+pub(crate) mod device;
+pub(crate) mod diag;
+pub(crate) mod graphics;
+pub(crate) mod interrupt;
+pub(crate) mod mm;
+pub(crate) mod platform;
+pub(crate) mod task;
+
+/// The kernel entry point, jumped to by the bootloader on the kernel stack.
+#[no_mangle]
+pub extern "sysv64" fn kernel_main(args: &'static KernelArgs) -> ! {
+    boot::init(args);
+    // The heap is backed on demand, so page faults must be handled from here on
+    interrupt::init_exceptions();
+    mm::init();
+    graphics::init();
+    diag::init();
+
+    info!("Tyto kernel v{} on x86_64", env!("CARGO_PKG_VERSION"));
+    boot::options().log_summary();
+
+    let layout = boot::layout();
+    info!(
+        "Kernel at {:#x}, stack at {:#x}, physical memory at {:#x}{}",
+        layout.kernel_base,
+        layout.stack_bottom,
+        layout.phys_map_offset,
+        if layout.randomized != 0 {
+            " (randomized)"
+        } else {
+            ""
+        }
+    );
+    mm::stack::init();
+    mm::frame::log_summary();
+    mm::heap::log_summary();
+    mm::slab::log_summary();
+    mm::region::log_summary();
+
+    match boot::args().kernel_digest() {
+        Some(digest) => info!("Kernel image {}", digest),
+        None => warn!("The bootloader did not report the kernel digest"),
+    }
+
+    if !graphics::framebuffer::is_available() {
+        warn!("No framebuffer, logging to serial only");
+    }
+
+    for module in boot::modules::modules() {
+        info!(
+            "Module {} at {:#x} ({} bytes)",
+            module.name,
+            module.phys_addr,
+            module.data.len()
+        );
+    }
+
+    info!("Initializing the kernel.");
+
+    platform::init();
+    // Firmware tables may be in boot services memory, they stay where they are
+    mm::frame::reclaim(&platform::firmware_tables());
+    interrupt::init();
+    device::init();
+    task::init();
+
+    if boot::options().ps2 {
+        EXECUTOR.lock().spawn(Task::new(diag::keys::run()));
+    }
+
+    info!("Kernel initialized.");
+
+    EXECUTOR.lock().run();
+}
@@ -0,0 +1,141 @@
+use core::{fmt, ops::Range};
+use x86_64::{
+    align_down,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use crate::{
+    data::IRQLock,
+    mm::{
+        frame,
+        vmm::{self, MapError},
+    },
+};
+
+const MAX_LAZY_REGIONS: usize = 32;
+
+/// Kept out of the heap, since the heap itself is backed on demand and the
+/// fault handler must not touch anything that can fault
+static LAZY_REGIONS: IRQLock<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    IRQLock::new([None; MAX_LAZY_REGIONS]);
+
+/// A range of kernel address space whose pages are allocated, zeroed and
+/// mapped the first time they are touched
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LazyRegion {
+    pub(crate) name: &'static str,
+    pub(crate) start: VirtAddr,
+    pub(crate) end: VirtAddr,
+    pub(crate) flags: PageTableFlags,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DemandError {
+    /// The address is not in a lazily backed region
+    NotLazy,
+    /// The page is present, so this is a protection fault
+    Protection(&'static str),
+    /// A write to a region that is not writable
+    ReadOnly(&'static str),
+    OutOfMemory(&'static str),
+    Map(&'static str, MapError),
+    /// The fault hit code that was editing the regions or the page tables
+    Reentrant,
+}
+
+impl fmt::Display for DemandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DemandError::NotLazy => f.write_str("not in a lazily backed region"),
+            DemandError::Protection(name) => write!(f, "protection violation in {}", name),
+            DemandError::ReadOnly(name) => write!(f, "write to read-only {}", name),
+            DemandError::OutOfMemory(name) => write!(f, "out of memory backing {}", name),
+            DemandError::Map(name, e) => write!(f, "could not map a page of {}: {}", name, e),
+            DemandError::Reentrant => f.write_str("fault while editing the page tables"),
+        }
+    }
+}
+
+/// Backs `range` with zeroed pages on first touch. The range must not be
+/// mapped already.
+pub(crate) fn register(name: &'static str, range: Range<VirtAddr>, flags: PageTableFlags) {
+    let mut regions = LAZY_REGIONS.lock();
+    let slot = regions
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many lazily backed regions");
+
+    *slot = Some(LazyRegion {
+        name,
+        start: range.start,
+        end: range.end,
+        flags,
+    });
+}
+
+/// Stops backing the region starting at `start`. Pages already backed stay
+/// mapped, the owner unmaps them.
+pub(crate) fn unregister(start: VirtAddr) {
+    let mut regions = LAZY_REGIONS.lock();
+    let slot = regions
+        .iter_mut()
+        .find(|slot| matches!(slot, Some(region) if region.start == start))
+        .expect("No lazily backed region there");
+
+    *slot = None;
+}
+
+/// Moves the end of the region starting at `start` to `end`, for regions
+/// that grow as they are used
+pub(crate) fn resize(start: VirtAddr, end: VirtAddr) {
+    let mut regions = LAZY_REGIONS.lock();
+    let region = regions
+        .iter_mut()
+        .flatten()
+        .find(|region| region.start == start)
+        .expect("No lazily backed region there");
+
+    region.end = end;
+}
+
+/// Resolves a page fault at `addr` by backing the page, if it is in a lazily
+/// backed region. Called from the page fault handler.
+pub(crate) fn handle_fault(addr: VirtAddr, code: PageFaultErrorCode) -> Result<(), DemandError> {
+    if LAZY_REGIONS.is_locked() || vmm::kernel().is_locked() {
+        return Err(DemandError::Reentrant);
+    }
+
+    let (name, flags) = LAZY_REGIONS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|region| region.start <= addr && addr < region.end)
+        .map(|region| (region.name, region.flags))
+        .ok_or(DemandError::NotLazy)?;
+
+    if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(DemandError::Protection(name));
+    }
+    if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !flags.contains(PageTableFlags::WRITABLE)
+    {
+        return Err(DemandError::ReadOnly(name));
+    }
+
+    let frame = frame::allocate_zeroed().ok_or(DemandError::OutOfMemory(name))?;
+    let page = VirtAddr::new(align_down(addr.as_u64(), Size4KiB::SIZE));
+
+    if let Err(e) = vmm::kernel()
+        .lock()
+        .map(page, frame.start_address(), Size4KiB::SIZE, flags)
+    {
+        unsafe { frame::free(PhysFrame::range(frame, frame + 1)) };
+        return Err(DemandError::Map(name, e));
+    }
+
+    Ok(())
+}
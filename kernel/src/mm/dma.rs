@@ -0,0 +1,306 @@
+use alloc::vec::Vec;
+use core::{
+    marker::PhantomData,
+    mem::size_of,
+    ops::{Deref, DerefMut, Index, IndexMut},
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use log::{info, warn};
+use x86_64::{
+    align_up,
+    structures::paging::{frame::PhysFrameRange, PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    data::IRQLock,
+    mm::{self, frame},
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Every buffer that has not been dropped yet
+static LIVE: IRQLock<Vec<LiveBuffer>> = IRQLock::new(Vec::new());
+
+/// How much physical memory a device can address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DmaLimit {
+    Any,
+    /// For devices with 32 bit address registers
+    Below4GiB,
+}
+
+impl DmaLimit {
+    /// The first frame number the device cannot reach
+    fn end_frame(self) -> usize {
+        match self {
+            DmaLimit::Any => usize::MAX,
+            DmaLimit::Below4GiB => (0x1_0000_0000 / Size4KiB::SIZE) as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LiveBuffer {
+    pub(crate) id: u64,
+    pub(crate) owner: &'static str,
+    pub(crate) bus_addr: u64,
+    pub(crate) size: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DmaStats {
+    pub(crate) buffers: usize,
+    pub(crate) bytes: usize,
+}
+
+/// Physically contiguous, zeroed memory a device can read and write. The CPU
+/// reaches it through the physical memory window, which is write-back, and
+/// x86 keeps DMA coherent with the caches, so no flushing is needed.
+///
+/// Freed when dropped, the device must be done with it by then.
+#[derive(Debug)]
+pub(crate) struct DmaBuffer {
+    id: u64,
+    frames: PhysFrameRange,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates `size` bytes aligned to `align`, which is at least 4K.
+    /// `owner` names the driver in leak reports.
+    pub(crate) fn new(
+        owner: &'static str,
+        size: usize,
+        align: usize,
+        limit: DmaLimit,
+    ) -> Option<Self> {
+        assert!(size > 0 && align.is_power_of_two());
+
+        let frame_size = Size4KiB::SIZE as usize;
+        let count = align_up(size as u64, Size4KiB::SIZE) as usize / frame_size;
+        let frames = frame::allocate_below(count, (align / frame_size).max(1), limit.end_frame())?;
+
+        let buffer = Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            frames,
+            size,
+        };
+        unsafe { buffer.as_mut_ptr::<u8>().write_bytes(0, count * frame_size) };
+
+        LIVE.lock().push(LiveBuffer {
+            id: buffer.id,
+            owner,
+            bus_addr: buffer.bus_addr(),
+            size,
+        });
+
+        Some(buffer)
+    }
+
+    /// The address the device uses. There is no IOMMU, so this is the
+    /// physical address.
+    pub(crate) fn bus_addr(&self) -> u64 {
+        self.frames.start.start_address().as_u64()
+    }
+
+    pub(crate) fn virt(&self) -> VirtAddr {
+        mm::phys_to_virt(self.frames.start.start_address())
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt().as_mut_ptr()
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_mut_ptr(), self.size) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let mut live = LIVE.lock();
+        if let Some(index) = live.iter().position(|buffer| buffer.id == self.id) {
+            live.swap_remove(index);
+        }
+        drop(live);
+
+        unsafe { frame::free(self.frames) };
+    }
+}
+
+/// Types a device may share with the CPU: plain data for which all zero bytes
+/// are a valid value, such as `#[repr(C)]` descriptors made of integers
+///
+/// # Safety
+/// The all zero bit pattern must be a valid value and the type must not
+/// contain references or pointers into the kernel.
+pub(crate) unsafe trait DmaSafe: Copy {}
+
+unsafe impl DmaSafe for u8 {}
+unsafe impl DmaSafe for u16 {}
+unsafe impl DmaSafe for u32 {}
+unsafe impl DmaSafe for u64 {}
+unsafe impl<T: DmaSafe, const N: usize> DmaSafe for [T; N] {}
+
+/// A single value in DMA memory, such as a command block
+#[derive(Debug)]
+pub(crate) struct DmaBox<T: DmaSafe> {
+    buffer: DmaBuffer,
+    _marker: PhantomData<T>,
+}
+
+impl<T: DmaSafe> DmaBox<T> {
+    pub(crate) fn new(owner: &'static str, limit: DmaLimit) -> Option<Self> {
+        Some(Self {
+            buffer: DmaBuffer::new(owner, size_of::<T>().max(1), Size4KiB::SIZE as usize, limit)?,
+            _marker: PhantomData,
+        })
+    }
+
+    pub(crate) fn bus_addr(&self) -> u64 {
+        self.buffer.bus_addr()
+    }
+}
+
+impl<T: DmaSafe> Deref for DmaBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.buffer.as_mut_ptr::<T>() }
+    }
+}
+
+impl<T: DmaSafe> DerefMut for DmaBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.buffer.as_mut_ptr::<T>() }
+    }
+}
+
+/// A ring of descriptors in DMA memory. Indexes wrap around, so producer and
+/// consumer counters can be used directly.
+#[derive(Debug)]
+pub(crate) struct DmaRing<T: DmaSafe> {
+    buffer: DmaBuffer,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: DmaSafe> DmaRing<T> {
+    /// Allocates `len` zeroed descriptors, the ring starting at a multiple of
+    /// `align`
+    pub(crate) fn new(
+        owner: &'static str,
+        len: usize,
+        align: usize,
+        limit: DmaLimit,
+    ) -> Option<Self> {
+        assert!(len > 0 && size_of::<T>() > 0);
+
+        Some(Self {
+            buffer: DmaBuffer::new(owner, len * size_of::<T>(), align, limit)?,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The bus address of the first descriptor, what the device is told
+    pub(crate) fn bus_addr(&self) -> u64 {
+        self.buffer.bus_addr()
+    }
+
+    /// The bus address of descriptor `index`
+    pub(crate) fn bus_addr_of(&self, index: usize) -> u64 {
+        self.bus_addr() + ((index % self.len) * size_of::<T>()) as u64
+    }
+
+    pub(crate) fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.buffer.as_mut_ptr(), self.len) }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.buffer.as_mut_ptr(), self.len) }
+    }
+
+    /// Reads descriptor `index` with a volatile read, for fields the device
+    /// writes back
+    pub(crate) fn read(&self, index: usize) -> T {
+        unsafe {
+            self.buffer
+                .as_mut_ptr::<T>()
+                .add(index % self.len)
+                .read_volatile()
+        }
+    }
+
+    /// Writes descriptor `index` with a volatile write, so that it is not
+    /// reordered past the doorbell write that hands it to the device
+    pub(crate) fn write(&mut self, index: usize, value: T) {
+        unsafe {
+            self.buffer
+                .as_mut_ptr::<T>()
+                .add(index % self.len)
+                .write_volatile(value)
+        }
+    }
+}
+
+impl<T: DmaSafe> Index<usize> for DmaRing<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        &self.as_slice()[index % self.len]
+    }
+}
+
+impl<T: DmaSafe> IndexMut<usize> for DmaRing<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len;
+        &mut self.as_mut_slice()[index % len]
+    }
+}
+
+/// A copy of every buffer still allocated, oldest first
+pub(crate) fn live_buffers() -> Vec<LiveBuffer> {
+    let mut live = LIVE.lock().clone();
+    live.sort_unstable_by_key(|buffer| buffer.id);
+    live
+}
+
+pub(crate) fn stats() -> DmaStats {
+    let live = LIVE.lock();
+
+    DmaStats {
+        buffers: live.len(),
+        bytes: live.iter().map(|buffer| buffer.size).sum(),
+    }
+}
+
+pub(crate) fn log_summary() {
+    let stats = stats();
+    info!("DMA: {} buffers, {} KiB", stats.buffers, stats.bytes / 1024);
+}
+
+/// Logs the buffers `owner` still holds, for drivers to call once they are
+/// shut down and should hold none
+pub(crate) fn report_leaks(owner: &'static str) {
+    for buffer in live_buffers().iter().filter(|buffer| buffer.owner == owner) {
+        warn!(
+            "DMA buffer #{} of {} leaked: {} bytes at bus address {:#x}",
+            buffer.id, buffer.owner, buffer.size, buffer.bus_addr
+        );
+    }
+}
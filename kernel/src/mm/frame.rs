@@ -0,0 +1,376 @@
+use alloc::vec::Vec;
+use boot_lib::PTE_MEM_TYPE;
+use core::{ops::Range, slice};
+use log::info;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use x86_64::{
+    align_up,
+    registers::control::Cr3,
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr,
+};
+
+use crate::{
+    boot,
+    data::{IRQLock, LateInit},
+    mm,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Frames below this are never handed out. The first MiB holds legacy BIOS
+/// structures and is where SMP trampolines will have to go.
+const LOW_MEMORY_END: u64 = 0x100000;
+
+static FRAMES: LateInit<IRQLock<BitmapFrameAllocator>> = LateInit::new();
+
+/// Memory left behind by the firmware and the bootloader, which the kernel
+/// may use once it no longer needs anything in it. Bootloader page tables
+/// are included, the ones still in use are kept.
+fn is_reclaimable(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+            | MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+    ) || ty == MemoryType::custom(PTE_MEM_TYPE)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameStats {
+    /// Frames managed by the allocator
+    pub(crate) total: usize,
+    /// Frames currently free
+    pub(crate) free: usize,
+}
+
+/// Hands out 4K physical frames, tracked with one bit per frame up to the end
+/// of the highest usable memory region. Only conventional memory is free at
+/// first, reclaimable memory is added by `reclaim`. Everything else,
+/// including the kernel, its stack, the kernel args, modules and UEFI runtime
+/// memory, is never free.
+pub(crate) struct BitmapFrameAllocator {
+    /// A set bit means the frame is free
+    bitmap: &'static mut [u64],
+    frames: usize,
+    total: usize,
+    free: usize,
+    /// Where the next search starts, so allocations do not rescan the
+    /// beginning of memory every time
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the memory map. The bitmap itself is carved
+    /// out of the first conventional region large enough for it.
+    fn new(memory_map: &[MemoryDescriptor]) -> Self {
+        let end = memory_map
+            .iter()
+            .filter(|desc| desc.ty == MemoryType::CONVENTIONAL || is_reclaimable(desc.ty))
+            .map(|desc| desc.phys_start + desc.page_count * FRAME_SIZE)
+            .max()
+            .expect("The memory map has no usable memory");
+
+        let frames = (end / FRAME_SIZE) as usize;
+        let words = (frames + 63) / 64;
+        let bitmap_pages = align_up(words as u64 * 8, FRAME_SIZE) / FRAME_SIZE;
+
+        let bitmap_phys = memory_map
+            .iter()
+            .find(|desc| {
+                desc.ty == MemoryType::CONVENTIONAL
+                    && desc.phys_start >= LOW_MEMORY_END
+                    && desc.page_count >= bitmap_pages
+            })
+            .map(|desc| PhysAddr::new(desc.phys_start))
+            .expect("No memory for the frame bitmap");
+
+        let bitmap = unsafe {
+            slice::from_raw_parts_mut(mm::phys_to_virt(bitmap_phys).as_mut_ptr::<u64>(), words)
+        };
+        bitmap.fill(0);
+
+        let mut allocator = Self {
+            bitmap,
+            frames,
+            total: 0,
+            free: 0,
+            next: 0,
+        };
+
+        for desc in memory_map
+            .iter()
+            .filter(|desc| desc.ty == MemoryType::CONVENTIONAL)
+        {
+            for frame in frames_of(desc) {
+                allocator.set_free(frame);
+            }
+        }
+
+        let bitmap_start = (bitmap_phys.as_u64() / FRAME_SIZE) as usize;
+        for frame in bitmap_start..bitmap_start + bitmap_pages as usize {
+            allocator.reserve(frame);
+        }
+
+        allocator.total = allocator.free;
+        allocator
+    }
+
+    /// Frees the reclaimable memory in `memory_map`, except for the frames
+    /// in `keep` and the page tables still in use, and returns how many
+    /// frames were freed
+    fn reclaim(&mut self, memory_map: &[MemoryDescriptor], keep: &[Range<usize>]) -> usize {
+        let free = self.free;
+
+        for desc in memory_map.iter().filter(|desc| is_reclaimable(desc.ty)) {
+            for frame in frames_of(desc) {
+                if !keep.iter().any(|range| range.contains(&frame)) {
+                    self.set_free(frame);
+                }
+            }
+        }
+
+        // The kernel is mapped through the bootloader's page tables, which
+        // stay allocated and can be freed like any other frame once they are
+        // unmapped. The firmware's went with the low half.
+        let (pml4, _) = Cr3::read();
+        self.reserve_page_tables(pml4.start_address(), 4);
+
+        // Only what actually became free, not frames that already were or
+        // the page tables taken back
+        let freed = self.free - free;
+        self.total += freed;
+        freed
+    }
+
+    fn is_free(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        if !self.is_free(frame) {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+            self.free += 1;
+        }
+    }
+
+    /// Marks a frame as used, whether or not it was free
+    fn reserve(&mut self, frame: usize) {
+        if frame < self.frames && self.is_free(frame) {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+            self.free -= 1;
+        }
+    }
+
+    /// Reserves the frames of every page table reachable from `table`
+    fn reserve_page_tables(&mut self, table: PhysAddr, level: u8) {
+        self.reserve((table.as_u64() / FRAME_SIZE) as usize);
+
+        if level == 1 {
+            return;
+        }
+
+        let table = unsafe { &*mm::phys_to_virt(table).as_ptr::<PageTable>() };
+        for entry in table.iter() {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                self.reserve_page_tables(entry.addr(), level - 1);
+            }
+        }
+    }
+
+    /// Finds `count` free frames in a row, starting at a multiple of `align`
+    /// frames, below frame `limit`
+    fn find(&self, count: usize, align: usize, limit: usize) -> Option<usize> {
+        let limit = limit.min(self.frames);
+        let search = |from: usize, to: usize| {
+            let mut start = align_up(from as u64, align as u64) as usize;
+
+            while start + count <= to {
+                match (start..start + count).find(|&frame| !self.is_free(frame)) {
+                    Some(used) => start = align_up(used as u64 + 1, align as u64) as usize,
+                    None => return Some(start),
+                }
+            }
+
+            None
+        };
+
+        let next = self.next.min(limit);
+        search(next, limit).or_else(|| search(0, (next + count).min(limit)))
+    }
+
+    /// Allocates `count` physically contiguous frames, the first one aligned
+    /// to `align` frames
+    pub(crate) fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> Option<PhysFrameRange> {
+        self.allocate_below(count, align, usize::MAX)
+    }
+
+    fn allocate_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: usize,
+    ) -> Option<PhysFrameRange> {
+        assert!(count > 0 && align.is_power_of_two());
+
+        let start = self.find(count, align, limit)?;
+        for frame in start..start + count {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+        }
+        self.free -= count;
+        self.next = start + count;
+
+        let first = PhysFrame::containing_address(PhysAddr::new(start as u64 * FRAME_SIZE));
+        Some(PhysFrame::range(first, first + count as u64))
+    }
+
+    /// Returns frames to the allocator
+    ///
+    /// # Safety
+    /// Nothing may use the frames afterwards.
+    pub(crate) unsafe fn free(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            let frame = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+            assert!(
+                frame < self.frames && !self.is_free(frame),
+                "Frame {:#x} freed twice or never allocated",
+                frame as u64 * FRAME_SIZE
+            );
+            self.set_free(frame);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            free: self.free,
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(1, 1).map(|range| range.start)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free(PhysFrame::range(frame, frame + 1));
+    }
+}
+
+/// The kernel's frame allocator, for APIs such as `Mapper` that want one
+/// passed in
+pub(crate) struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        free(PhysFrame::range(frame, frame + 1));
+    }
+}
+
+pub(crate) fn allocate() -> Option<PhysFrame> {
+    FRAMES.lock().allocate_frame()
+}
+
+/// Allocates a frame and fills it with zeros
+pub(crate) fn allocate_zeroed() -> Option<PhysFrame> {
+    let frame = allocate()?;
+    unsafe {
+        mm::phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, FRAME_SIZE as usize);
+    }
+
+    Some(frame)
+}
+
+/// Allocates `count` physically contiguous frames, the first one aligned to
+/// `align` frames
+pub(crate) fn allocate_contiguous(count: usize, align: usize) -> Option<PhysFrameRange> {
+    FRAMES.lock().allocate_contiguous(count, align)
+}
+
+/// Like `allocate_contiguous`, but every frame lies below frame number
+/// `limit`, for devices that cannot reach all of memory
+pub(crate) fn allocate_below(count: usize, align: usize, limit: usize) -> Option<PhysFrameRange> {
+    FRAMES.lock().allocate_below(count, align, limit)
+}
+
+/// Returns frames to the allocator
+///
+/// # Safety
+/// Nothing may use the frames afterwards.
+pub(crate) unsafe fn free(range: PhysFrameRange) {
+    FRAMES.lock().free(range);
+}
+
+/// The frames covered by a memory map entry, leaving out low memory
+fn frames_of(desc: &MemoryDescriptor) -> Range<usize> {
+    let start = (desc.phys_start.max(LOW_MEMORY_END) / FRAME_SIZE) as usize;
+    let end = ((desc.phys_start + desc.page_count * FRAME_SIZE) / FRAME_SIZE) as usize;
+
+    start..end.max(start)
+}
+
+/// Hands the memory the firmware and the bootloader used to the allocator,
+/// except for the physical ranges in `keep`, such as firmware tables. Must
+/// run once nothing else reads from boot services or bootloader memory any
+/// more, UEFI runtime memory is left alone.
+pub(crate) fn reclaim(keep: &[Range<PhysAddr>]) {
+    let memory_map = boot::args()
+        .memory_map()
+        .expect("The bootloader did not pass a memory map");
+    let keep: Vec<Range<usize>> = keep
+        .iter()
+        .map(|range| {
+            let start = range.start.as_u64() / FRAME_SIZE;
+            let end = align_up(range.end.as_u64(), FRAME_SIZE) / FRAME_SIZE;
+            start as usize..end as usize
+        })
+        .collect();
+
+    let frames = FRAMES.lock().reclaim(memory_map, &keep);
+    info!(
+        "Reclaimed {} MiB of boot memory",
+        frames as u64 * FRAME_SIZE / 1024 / 1024
+    );
+}
+
+pub(crate) fn stats() -> FrameStats {
+    FRAMES.lock().stats()
+}
+
+pub(crate) fn log_summary() {
+    let stats = stats();
+    info!(
+        "Physical memory: {} MiB usable, {} MiB free",
+        stats.total as u64 * FRAME_SIZE / 1024 / 1024,
+        stats.free as u64 * FRAME_SIZE / 1024 / 1024
+    );
+}
+
+pub(crate) fn init() {
+    let memory_map = boot::args()
+        .memory_map()
+        .expect("The bootloader did not pass a memory map");
+
+    FRAMES.init(|| IRQLock::new(BitmapFrameAllocator::new(memory_map)));
+}
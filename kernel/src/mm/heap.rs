@@ -0,0 +1,193 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+use log::info;
+use x86_64::{
+    align_up,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+#[cfg(feature = "heap-debug")]
+use crate::mm::heap_debug;
+use crate::{
+    data::IRQLock,
+    mm::{demand, frame, slab},
+};
+
+/// The heap grows upwards from here, into a range used for nothing else
+pub(crate) const HEAP_START: u64 = 0xFFFF_E000_0000_0000;
+pub(crate) const HEAP_MAX_SIZE: u64 = 0x100_0000_0000;
+
+const HEAP_INITIAL_SIZE: u64 = 1024 * 1024;
+/// The heap grows by at least this much, so that a run of small allocations
+/// does not extend it one page at a time
+const HEAP_GROW_MIN: u64 = 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap(IRQLock::new(HeapState {
+    heap: Heap::empty(),
+    allocations: 0,
+    peak: 0,
+}));
+
+/// A linked list heap in its own virtual range, whose pages are backed by the
+/// page fault handler the first time they are touched. Its statistics do not
+/// cover the slab size classes.
+struct KernelHeap(IRQLock<HeapState>);
+
+struct HeapState {
+    heap: Heap,
+    /// Live allocations
+    allocations: usize,
+    /// Most bytes ever in use at once
+    peak: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HeapStats {
+    /// Bytes the heap spans, pages are only backed once touched
+    pub(crate) size: usize,
+    pub(crate) used: usize,
+    pub(crate) peak: usize,
+    pub(crate) allocations: usize,
+}
+
+impl HeapState {
+    /// Adds at least `by` more bytes at the top of the heap. Fails if the
+    /// heap range is exhausted, running out of physical memory only shows
+    /// once the new pages are touched.
+    fn grow(&mut self, by: usize) -> Option<()> {
+        let top = self.heap.top() as u64;
+        let by = align_up((by as u64).max(HEAP_GROW_MIN), Size4KiB::SIZE);
+        if top + by > HEAP_START + HEAP_MAX_SIZE {
+            return None;
+        }
+
+        // Extending writes to the new pages, so they must be backed first
+        demand::resize(VirtAddr::new(HEAP_START), VirtAddr::new(top + by));
+        unsafe { self.heap.extend(by as usize) };
+
+        Some(())
+    }
+}
+
+// Small allocations go to the slab size classes, everything else to the
+// linked list heap
+impl KernelHeap {
+    unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        if let Some(cache) = slab::size_class(layout) {
+            return cache.alloc().map_or(ptr::null_mut(), NonNull::as_ptr);
+        }
+
+        let mut state = self.0.lock();
+
+        let block = match state.heap.allocate_first_fit(layout) {
+            Ok(block) => block,
+            Err(()) => {
+                // Enough for the allocation wherever the free list ends
+                if state.grow(layout.size() + layout.align()).is_none() {
+                    return ptr::null_mut();
+                }
+
+                match state.heap.allocate_first_fit(layout) {
+                    Ok(block) => block,
+                    Err(()) => return ptr::null_mut(),
+                }
+            }
+        };
+
+        state.allocations += 1;
+        state.peak = state.peak.max(state.heap.used());
+
+        block.as_ptr()
+    }
+
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(cache) = slab::size_class(layout) {
+            return cache.free(NonNull::new_unchecked(ptr));
+        }
+
+        let mut state = self.0.lock();
+
+        state.heap.deallocate(NonNull::new_unchecked(ptr), layout);
+        state.allocations -= 1;
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_raw(layout)
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_raw(ptr, layout)
+    }
+
+    // Kept as a frame of its own, `heap_debug` skips it in traces
+    #[cfg(feature = "heap-debug")]
+    #[inline(never)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        heap_debug::alloc(layout, |layout| self.alloc_raw(layout))
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        heap_debug::dealloc(ptr, layout, |ptr, layout| self.dealloc_raw(ptr, layout))
+    }
+}
+
+pub(crate) fn stats() -> HeapStats {
+    let state = ALLOCATOR.0.lock();
+
+    HeapStats {
+        size: state.heap.size(),
+        used: state.heap.used(),
+        peak: state.peak,
+        allocations: state.allocations,
+    }
+}
+
+pub(crate) fn log_summary() {
+    let stats = stats();
+    info!(
+        "Heap: {} KiB spanned, {} KiB in {} allocations, peak {} KiB",
+        stats.size / 1024,
+        stats.used / 1024,
+        stats.allocations,
+        stats.peak / 1024
+    );
+}
+
+pub(crate) fn init() {
+    // Only the part the heap spans, `HeapState::grow` moves the end along
+    demand::register(
+        "kernel heap",
+        VirtAddr::new(HEAP_START)..VirtAddr::new(HEAP_START + HEAP_INITIAL_SIZE),
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    );
+
+    unsafe {
+        ALLOCATOR
+            .0
+            .lock()
+            .heap
+            .init(HEAP_START as usize, HEAP_INITIAL_SIZE as usize);
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    // The heap lock is free again, but allocating here would fail the same way
+    let heap = stats();
+    let frames = frame::stats();
+
+    panic!(
+        "Kernel heap exhausted while allocating {:?}: heap {} of {} bytes used in {} allocations, {} of {} frames free",
+        layout, heap.used, heap.size, heap.allocations, frames.free, frames.total
+    )
+}
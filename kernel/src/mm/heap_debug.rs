@@ -0,0 +1,310 @@
+use core::{
+    alloc::Layout,
+    arch::asm,
+    fmt::{self, Write},
+    mem::{align_of, size_of},
+    ptr, slice,
+};
+use x86_64::align_up;
+
+use crate::{boot, data::IRQLock, device::serial::SERIAL1, mm::stack};
+
+/// Return addresses kept per allocation
+const TRACE_DEPTH: usize = 4;
+/// Frames of the allocator itself left out of traces: `alloc` here and
+/// `KernelHeap::alloc`, neither of which is inlined
+const TRACE_SKIP: usize = 2;
+
+/// Guard bytes directly before and after every allocation
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xFD;
+/// Fresh allocations are filled with this, to make use of uninitialized
+/// memory stand out
+const ALLOC_POISON: u8 = 0xCD;
+/// Freed allocations are filled with this, to make use after free stand out
+const FREE_POISON: u8 = 0xDD;
+
+const MAGIC_LIVE: u64 = u64::from_le_bytes(*b"HEAPLIVE");
+const MAGIC_FREED: u64 = u64::from_le_bytes(*b"HEAPFREE");
+
+/// Allocation sites kept apart in a leak report, the rest are lumped together
+const REPORT_SITES: usize = 32;
+
+static LIVE: IRQLock<LiveList> = IRQLock::new(LiveList {
+    head: ptr::null_mut(),
+    next_id: 0,
+    allocations: 0,
+    bytes: 0,
+});
+
+/// Placed right before the front red zone of every allocation, and linked
+/// into the list of live allocations
+#[repr(C)]
+struct Header {
+    prev: *mut Header,
+    next: *mut Header,
+    size: usize,
+    id: u64,
+    trace: [u64; TRACE_DEPTH],
+    /// Last, as far as possible from the start of the block, where the slab
+    /// and linked list allocators keep their free list links
+    magic: u64,
+}
+
+struct LiveList {
+    head: *mut Header,
+    next_id: u64,
+    allocations: usize,
+    bytes: usize,
+}
+
+unsafe impl Send for LiveList {}
+
+/// Bytes in front of the allocation, the header and the front red zone
+fn front_size(layout: &Layout) -> usize {
+    align_up(
+        (size_of::<Header>() + RED_ZONE) as u64,
+        layout.align() as u64,
+    ) as usize
+}
+
+/// The layout actually requested from the heap
+fn padded(layout: &Layout) -> Layout {
+    Layout::from_size_align(
+        front_size(layout) + layout.size() + RED_ZONE,
+        layout.align().max(align_of::<Header>()),
+    )
+    .expect("Allocation too large for red zones")
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.sub(RED_ZONE + size_of::<Header>()) as *mut Header
+}
+
+/// Return addresses of the callers, found by following the frame pointers.
+/// Only frames on the kernel stack are followed, allocations made on another
+/// stack get an empty trace.
+#[inline(never)]
+fn backtrace() -> [u64; TRACE_DEPTH] {
+    let stack = stack::range();
+    // A frame holds the caller's frame pointer and the return address
+    let on_stack = |frame: *const u64| {
+        let addr = frame as u64;
+        addr % 8 == 0 && stack.start.as_u64() <= addr && addr + 16 <= stack.end.as_u64()
+    };
+
+    let mut trace = [0; TRACE_DEPTH];
+    let mut frame: *const u64;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack)) };
+
+    for i in 0..TRACE_SKIP + TRACE_DEPTH {
+        if !on_stack(frame) {
+            break;
+        }
+
+        let (next, ret) = unsafe { (*frame as *const u64, *frame.add(1)) };
+        if i >= TRACE_SKIP {
+            trace[i - TRACE_SKIP] = ret;
+        }
+
+        // Callers' frames are further up the stack, anything else is the end
+        // of the chain
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+
+    trace
+}
+
+/// Allocates with red zones and a header in front, poisons the allocation and
+/// records where it was made
+#[inline(never)]
+pub(super) unsafe fn alloc(layout: Layout, raw: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let block = raw(padded(&layout));
+    if block.is_null() {
+        return block;
+    }
+
+    let ptr = block.add(front_size(&layout));
+    let header = header(ptr);
+
+    block.write_bytes(RED_ZONE_BYTE, header as usize - block as usize);
+    ptr.sub(RED_ZONE).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+    ptr.add(layout.size()).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+    ptr.write_bytes(ALLOC_POISON, layout.size());
+
+    let mut live = LIVE.lock();
+    header.write(Header {
+        prev: ptr::null_mut(),
+        next: live.head,
+        size: layout.size(),
+        id: live.next_id,
+        trace: backtrace(),
+        magic: MAGIC_LIVE,
+    });
+    if !live.head.is_null() {
+        (*live.head).prev = header;
+    }
+    live.head = header;
+    live.next_id += 1;
+    live.allocations += 1;
+    live.bytes += layout.size();
+
+    ptr
+}
+
+/// Checks the header and the red zones, poisons the allocation and frees it.
+/// Panics on double frees and overflows, naming where the allocation was
+/// made.
+pub(super) unsafe fn dealloc(ptr: *mut u8, layout: Layout, raw: impl FnOnce(*mut u8, Layout)) {
+    let header = header(ptr);
+
+    match (*header).magic {
+        MAGIC_LIVE => {}
+        MAGIC_FREED => panic!("Heap: double free of {:p}, {}", ptr, Site(&(*header).trace)),
+        _ => panic!(
+            "Heap: free of {:p}, which was not allocated or whose header was overwritten",
+            ptr
+        ),
+    }
+    if (*header).size != layout.size() {
+        panic!(
+            "Heap: {:p} freed with size {}, allocated with {}, {}",
+            ptr,
+            layout.size(),
+            (*header).size,
+            Site(&(*header).trace)
+        );
+    }
+
+    let front = slice::from_raw_parts(ptr.sub(RED_ZONE), RED_ZONE);
+    let rear = slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE);
+    if let Some(i) = rear.iter().position(|&b| b != RED_ZONE_BYTE) {
+        panic!(
+            "Heap: overflow past the end of {:p} ({} bytes) at offset {}, {}",
+            ptr,
+            layout.size(),
+            layout.size() + i,
+            Site(&(*header).trace)
+        );
+    }
+    if front.iter().any(|&b| b != RED_ZONE_BYTE) {
+        panic!(
+            "Heap: underflow before the start of {:p}, {}",
+            ptr,
+            Site(&(*header).trace)
+        );
+    }
+
+    let mut live = LIVE.lock();
+    if (*header).prev.is_null() {
+        live.head = (*header).next;
+    } else {
+        (*(*header).prev).next = (*header).next;
+    }
+    if !(*header).next.is_null() {
+        (*(*header).next).prev = (*header).prev;
+    }
+    live.allocations -= 1;
+    live.bytes -= layout.size();
+    drop(live);
+
+    (*header).magic = MAGIC_FREED;
+    ptr.write_bytes(FREE_POISON, layout.size());
+
+    let block = ptr.sub(front_size(&layout));
+    raw(block, padded(&layout));
+}
+
+/// Formats a trace with addresses as linked, to feed to `addr2line`
+struct Site<'a>(&'a [u64; TRACE_DEPTH]);
+
+impl fmt::Display for Site<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let slide = boot::layout().kernel_slide;
+
+        f.write_str("allocated at")?;
+        for &ret in self.0.iter().take_while(|&&ret| ret != 0) {
+            write!(f, " {:#x}", ret.wrapping_sub(slide))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SiteTotal {
+    trace: [u64; TRACE_DEPTH],
+    allocations: usize,
+    bytes: usize,
+}
+
+/// Prints every live allocation over serial, grouped by where it was made and
+/// largest first. Addresses are as linked, to feed to `addr2line`.
+pub(crate) fn report_leaks() {
+    let mut sites = [SiteTotal {
+        trace: [0; TRACE_DEPTH],
+        allocations: 0,
+        bytes: 0,
+    }; REPORT_SITES];
+    let mut used = 0;
+    let mut other = (0, 0);
+
+    // Nothing is allocated while the list is locked, or this would deadlock
+    let (allocations, bytes) = {
+        let live = LIVE.lock();
+        let mut header = live.head;
+
+        while let Some(h) = unsafe { header.as_ref() } {
+            match sites[..used].iter_mut().find(|site| site.trace == h.trace) {
+                Some(site) => {
+                    site.allocations += 1;
+                    site.bytes += h.size;
+                }
+                None if used < REPORT_SITES => {
+                    sites[used] = SiteTotal {
+                        trace: h.trace,
+                        allocations: 1,
+                        bytes: h.size,
+                    };
+                    used += 1;
+                }
+                None => {
+                    other.0 += 1;
+                    other.1 += h.size;
+                }
+            }
+            header = h.next;
+        }
+
+        (live.allocations, live.bytes)
+    };
+
+    let sites = &mut sites[..used];
+    sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+
+    let mut serial = SERIAL1.lock();
+    let _ = writeln!(
+        serial,
+        "Heap leaks: {} live allocations, {} bytes",
+        allocations, bytes
+    );
+    for site in sites.iter() {
+        let _ = writeln!(
+            serial,
+            "  {} bytes in {} allocations, {}",
+            site.bytes,
+            site.allocations,
+            Site(&site.trace)
+        );
+    }
+    if other.0 > 0 {
+        let _ = writeln!(
+            serial,
+            "  {} bytes in {} allocations from other sites",
+            other.1, other.0
+        );
+    }
+}
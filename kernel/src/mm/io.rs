@@ -0,0 +1,292 @@
+use alloc::vec::Vec;
+use core::{arch::asm, fmt, ops::Range};
+use x86_64::{
+    align_down, align_up,
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    boot,
+    data::IRQLock,
+    mm::{
+        self, region,
+        vmm::{self, AddressSpace, MapError},
+    },
+};
+
+const IA32_PAT: u32 = 0x277;
+
+/// The physical range and mode of every live `ioremap` mapping, which is also
+/// the mode of the physical memory window's alias of that range
+static MAPPED: IRQLock<Vec<(Range<u64>, CacheMode)>> = IRQLock::new(Vec::new());
+
+// PAT memory types
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WP: u64 = 0x05;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+/// The power-on layout with entry 1 turned from WT into WC. Pages select an
+/// entry with PWT and PCD only, which leaves the PAT bit free for huge pages.
+const PAT: [u64; 8] = [
+    PAT_WB,
+    PAT_WC,
+    PAT_UC_MINUS,
+    PAT_UC,
+    PAT_WB,
+    PAT_WP,
+    PAT_UC_MINUS,
+    PAT_WT,
+];
+
+/// How the CPU caches a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CacheMode {
+    WriteBack,
+    /// Writes are buffered and merged, reads are uncached. For framebuffers.
+    WriteCombining,
+    /// For device registers, every access goes to the device in order
+    Uncached,
+}
+
+impl CacheMode {
+    /// The page table flags that select this mode's PAT entry
+    pub(crate) fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum IoRemapError {
+    /// The dynamic virtual range is full
+    NoVirtualSpace,
+    /// Part of the range is already mapped with another mode
+    ModeConflict(CacheMode),
+    Map(MapError),
+}
+
+impl fmt::Display for IoRemapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoRemapError::NoVirtualSpace => f.write_str("out of virtual address space"),
+            IoRemapError::ModeConflict(mode) => write!(f, "already mapped as {:?}", mode),
+            IoRemapError::Map(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Device memory mapped into the kernel address space
+#[derive(Debug)]
+pub(crate) struct IoMapping {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: u64,
+    mode: CacheMode,
+    /// The page aligned range actually mapped
+    region: VirtAddr,
+    region_size: u64,
+}
+
+impl IoMapping {
+    pub(crate) fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub(crate) fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+}
+
+/// Maps `size` bytes of device memory at `phys` with caching mode `mode`,
+/// in its own region of the dynamic virtual range. The physical memory
+/// window's alias of the same memory is switched to `mode` too, since mapping
+/// memory with different cache modes at once is unsupported. Memory can be
+/// mapped more than once, but only with the same mode.
+pub(crate) fn ioremap(
+    name: &'static str,
+    phys: PhysAddr,
+    size: u64,
+    mode: CacheMode,
+) -> Result<IoMapping, IoRemapError> {
+    let start = align_down(phys.as_u64(), Size4KiB::SIZE);
+    let region_size = align_up(phys.as_u64() + size, Size4KiB::SIZE) - start;
+    let range = start..start + region_size;
+
+    let mut mapped = MAPPED.lock();
+    if let Some((_, other)) = mapped
+        .iter()
+        .find(|(other, other_mode)| overlaps(other, &range) && *other_mode != mode)
+    {
+        return Err(IoRemapError::ModeConflict(*other));
+    }
+
+    // Matching the alignment of large ranges lets them use 2M pages
+    let align = if region_size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    };
+    let region = region::allocate(name, region_size, align).ok_or(IoRemapError::NoVirtualSpace)?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | mode.flags();
+
+    // Reserved up front, the heap must not grow while the page tables are
+    // locked
+    mapped.reserve(1);
+    let restore = unaliased(&mapped, range.clone());
+
+    let mut kernel = vmm::kernel().lock();
+    let result = kernel
+        .map(region, PhysAddr::new(start), region_size, flags)
+        .and_then(|()| set_window_mode(&mut kernel, PhysAddr::new(start), region_size, mode));
+    if let Err(e) = result {
+        // Whatever was mapped or changed before the failure goes again
+        for range in restore {
+            let _ = set_window_mode(
+                &mut kernel,
+                PhysAddr::new(range.start),
+                range.end - range.start,
+                CacheMode::WriteBack,
+            );
+        }
+        let _ = kernel.unmap(region, region_size);
+        drop(kernel);
+        region::release(region);
+        return Err(IoRemapError::Map(e));
+    }
+    drop(kernel);
+
+    mapped.push((range, mode));
+
+    Ok(IoMapping {
+        phys,
+        virt: region + (phys.as_u64() - start),
+        size,
+        mode,
+        region,
+        region_size,
+    })
+}
+
+/// Unmaps device memory mapped with `ioremap`. The physical memory window's
+/// alias goes back to write-back where no other mapping covers the memory.
+pub(crate) fn iounmap(mapping: IoMapping) {
+    let start = align_down(mapping.phys.as_u64(), Size4KiB::SIZE);
+    let range = start..start + mapping.region_size;
+
+    let mut mapped = MAPPED.lock();
+    let index = mapped
+        .iter()
+        .position(|(other, mode)| *other == range && *mode == mapping.mode)
+        .expect("Device memory mapping vanished");
+    mapped.swap_remove(index);
+    let restore = unaliased(&mapped, range);
+
+    let mut kernel = vmm::kernel().lock();
+    kernel
+        .unmap(mapping.region, mapping.region_size)
+        .expect("Device memory mapping vanished");
+    for range in restore {
+        set_window_mode(
+            &mut kernel,
+            PhysAddr::new(range.start),
+            range.end - range.start,
+            CacheMode::WriteBack,
+        )
+        .expect("Could not restore the physical memory window");
+    }
+    drop(kernel);
+
+    region::release(mapping.region);
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// The parts of `range` that no mapping in `mapped` covers
+fn unaliased(mapped: &[(Range<u64>, CacheMode)], range: Range<u64>) -> Vec<Range<u64>> {
+    let mut covered: Vec<Range<u64>> = mapped
+        .iter()
+        .map(|(other, _)| other.clone())
+        .filter(|other| overlaps(other, &range))
+        .collect();
+    covered.sort_unstable_by_key(|other| other.start);
+
+    let mut gaps = Vec::new();
+    let mut start = range.start;
+    for other in covered {
+        if other.start > start {
+            gaps.push(start..other.start);
+        }
+        start = start.max(other.end);
+    }
+    if start < range.end {
+        gaps.push(start..range.end);
+    }
+
+    gaps
+}
+
+/// Switches the physical memory window's alias of `size` bytes at `start` to
+/// `mode`, leaving the other flags of each page alone. Memory beyond the end
+/// of the window has no alias.
+fn set_window_mode(
+    kernel: &mut AddressSpace,
+    start: PhysAddr,
+    size: u64,
+    mode: CacheMode,
+) -> Result<(), MapError> {
+    let window_size = boot::layout().phys_map_size;
+    if start.as_u64() >= window_size {
+        return Ok(());
+    }
+    let size = size.min(window_size - start.as_u64());
+    let alias = mm::phys_to_virt(start);
+
+    kernel.split_huge_pages(alias, size)?;
+    kernel.update_flags(alias, size, |flags| {
+        (flags - CacheMode::Uncached.flags()) | mode.flags()
+    })?;
+
+    // Lines cached while the alias was write-back must not be written back
+    // over what the device shows later
+    unsafe { asm!("wbinvd", options(nostack)) };
+
+    Ok(())
+}
+
+/// Programs the PAT so that `CacheMode` flags select the intended types
+pub(crate) fn init() {
+    let pat = PAT
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, ty)| pat | ty << (i * 8));
+
+    unsafe {
+        // Lines cached under the old types must not survive the change
+        asm!("wbinvd", options(nostack));
+        Msr::new(IA32_PAT).write(pat);
+        tlb::flush_all();
+    }
+}
@@ -0,0 +1,34 @@
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::boot;
+
+pub(crate) mod demand;
+pub(crate) mod dma;
+pub(crate) mod frame;
+pub(crate) mod heap;
+#[cfg(feature = "heap-debug")]
+pub(crate) mod heap_debug;
+pub(crate) mod io;
+pub(crate) mod region;
+pub(crate) mod slab;
+pub(crate) mod stack;
+pub(crate) mod vmm;
+
+/// Returns the address of `phys` in the physical memory window
+pub(crate) fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(phys.as_u64() + boot::layout().phys_map_offset)
+}
+
+/// Returns the physical address of `virt`, which must be in the physical
+/// memory window
+pub(crate) fn virt_to_phys(virt: VirtAddr) -> PhysAddr {
+    PhysAddr::new(virt.as_u64() - boot::layout().phys_map_offset)
+}
+
+pub(crate) fn init() {
+    frame::init();
+    vmm::init();
+    io::init();
+    heap::init();
+    region::init();
+}
@@ -0,0 +1,144 @@
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
+use log::info;
+use x86_64::{align_up, VirtAddr};
+
+use crate::{
+    boot,
+    data::IRQLock,
+    mm::heap::{HEAP_MAX_SIZE, HEAP_START},
+};
+
+/// Virtual space handed out by `allocate`, for mappings that can go anywhere
+pub(crate) const DYNAMIC_RANGE: Range<u64> = 0xFFFF_E100_0000_0000..0xFFFF_E200_0000_0000;
+
+static REGIONS: IRQLock<Vec<Region>> = IRQLock::new(Vec::new());
+
+/// A named range of kernel virtual address space
+#[derive(Debug, Clone)]
+pub(crate) struct Region {
+    pub(crate) name: &'static str,
+    pub(crate) range: Range<VirtAddr>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct OverlapError {
+    pub(crate) name: &'static str,
+    pub(crate) with: &'static str,
+}
+
+impl fmt::Display for OverlapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} overlaps {}", self.name, self.with)
+    }
+}
+
+fn overlaps(a: &Range<VirtAddr>, b: &Range<VirtAddr>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Claims a fixed range of virtual address space
+pub(crate) fn reserve(name: &'static str, range: Range<VirtAddr>) -> Result<(), OverlapError> {
+    let mut regions = REGIONS.lock();
+
+    if let Some(other) = regions.iter().find(|other| overlaps(&other.range, &range)) {
+        return Err(OverlapError {
+            name,
+            with: other.name,
+        });
+    }
+
+    let index = regions.partition_point(|other| other.range.start < range.start);
+    regions.insert(index, Region { name, range });
+
+    Ok(())
+}
+
+/// Finds and claims `size` bytes of virtual address space in
+/// `DYNAMIC_RANGE`, aligned to `align`
+pub(crate) fn allocate(name: &'static str, size: u64, align: u64) -> Option<VirtAddr> {
+    let mut regions = REGIONS.lock();
+    let mut start = align_up(DYNAMIC_RANGE.start, align);
+
+    // Regions are sorted, so the first gap that fits is found in one pass
+    for other in regions.iter() {
+        let end = start + size;
+        if end > DYNAMIC_RANGE.end {
+            return None;
+        }
+        if other.range.start.as_u64() >= end {
+            break;
+        }
+        if other.range.end.as_u64() > start {
+            start = align_up(other.range.end.as_u64(), align);
+        }
+    }
+
+    if start + size > DYNAMIC_RANGE.end {
+        return None;
+    }
+
+    let range = VirtAddr::new(start)..VirtAddr::new(start + size);
+    let index = regions.partition_point(|other| other.range.start < range.start);
+    regions.insert(index, Region { name, range });
+
+    Some(VirtAddr::new(start))
+}
+
+/// Gives back the region starting at `start`
+pub(crate) fn release(start: VirtAddr) {
+    let mut regions = REGIONS.lock();
+
+    match regions
+        .iter()
+        .position(|region| region.range.start == start)
+    {
+        Some(index) => {
+            regions.remove(index);
+        }
+        None => panic!("No virtual region at {:?}", start),
+    }
+}
+
+/// A copy of every region, in address order
+pub(crate) fn regions() -> Vec<Region> {
+    REGIONS.lock().clone()
+}
+
+pub(crate) fn log_summary() {
+    for region in regions() {
+        info!(
+            "Virtual {:?} - {:?} {}",
+            region.range.start, region.range.end, region.name
+        );
+    }
+}
+
+/// Reserves what the bootloader set up and the fixed kernel ranges
+pub(crate) fn init() {
+    let layout = boot::layout();
+    let range = |start: u64, size: u64| VirtAddr::new(start)..VirtAddr::new(start + size);
+
+    let fixed = [
+        (
+            "physical memory window",
+            range(layout.phys_map_offset, layout.phys_map_size),
+        ),
+        (
+            "kernel image",
+            range(layout.kernel_base, layout.kernel_size),
+        ),
+        (
+            "kernel stack",
+            range(
+                layout.stack_bottom - layout.stack_size - layout.stack_guard_size,
+                layout.stack_guard_size + layout.stack_size,
+            ),
+        ),
+        ("kernel heap", range(HEAP_START, HEAP_MAX_SIZE)),
+    ];
+
+    for (name, range) in fixed {
+        reserve(name, range).expect("Kernel virtual regions overlap");
+    }
+}
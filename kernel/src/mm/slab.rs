@@ -0,0 +1,392 @@
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+use log::info;
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PageSize, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    data::IRQLock,
+    mm::{self, frame},
+};
+
+/// New slabs are made large enough for at least this many objects
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Allocations up to this size are served by the size class caches
+pub(crate) const MAX_SIZE_CLASS: usize = 2048;
+
+static SIZE_CLASSES: [SlabCache; 8] = [
+    SlabCache::new("kmalloc-16", size_class_layout(16), None),
+    SlabCache::new("kmalloc-32", size_class_layout(32), None),
+    SlabCache::new("kmalloc-64", size_class_layout(64), None),
+    SlabCache::new("kmalloc-128", size_class_layout(128), None),
+    SlabCache::new("kmalloc-256", size_class_layout(256), None),
+    SlabCache::new("kmalloc-512", size_class_layout(512), None),
+    SlabCache::new("kmalloc-1024", size_class_layout(1024), None),
+    SlabCache::new("kmalloc-2048", size_class_layout(2048), None),
+];
+
+/// Every cache that has allocated a slab, linked through `SlabCache::next`
+static CACHES: AtomicPtr<SlabCache> = AtomicPtr::new(ptr::null_mut());
+
+const fn size_class_layout(size: usize) -> Layout {
+    match Layout::from_size_align(size, size) {
+        Ok(layout) => layout,
+        Err(_) => panic!("Bad size class"),
+    }
+}
+
+/// The size class cache for `layout`, if it is small enough for one
+pub(crate) fn size_class(layout: Layout) -> Option<&'static SlabCache> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(16)
+        .next_power_of_two();
+    if size > MAX_SIZE_CLASS {
+        return None;
+    }
+
+    Some(&SIZE_CLASSES[size.trailing_zeros() as usize - 4])
+}
+
+/// Placed at the start of every slab, which is aligned to its own size so
+/// that the header of any object can be found by masking its address
+#[repr(C)]
+struct SlabHeader {
+    free: *mut FreeObject,
+    in_use: usize,
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// A doubly linked list of slabs
+struct SlabList {
+    head: *mut SlabHeader,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+    }
+}
+
+struct CacheState {
+    /// Slabs with at least one free object
+    partial: SlabList,
+    full: SlabList,
+    slabs: usize,
+    /// Slabs without any object in use, one of them is kept around
+    empty: usize,
+    in_use: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CacheStats {
+    pub(crate) name: &'static str,
+    pub(crate) object_size: usize,
+    pub(crate) in_use: usize,
+    /// Objects the current slabs have room for
+    pub(crate) capacity: usize,
+    pub(crate) slabs: usize,
+    pub(crate) slab_size: usize,
+}
+
+impl CacheStats {
+    /// The share of slab memory, in percent, that does not hold a live
+    /// object, counting headers, padding and free slots
+    pub(crate) fn fragmentation(&self) -> usize {
+        let total = self.slabs * self.slab_size;
+        if total == 0 {
+            return 0;
+        }
+
+        100 - self.in_use * self.object_size * 100 / total
+    }
+}
+
+/// A cache of equally sized objects, carved out of slabs of physically
+/// contiguous frames. Allocating and freeing are O(1).
+///
+/// Caches for a particular type are declared as statics and used through
+/// the `Allocator` trait, as the executor does with `TASK_CACHE`.
+pub(crate) struct SlabCache {
+    name: &'static str,
+    layout: Layout,
+    /// Distance between objects
+    stride: usize,
+    /// Where the free list link is kept within a free object. With a
+    /// constructor it goes past the end of the object, so that freeing does
+    /// not clobber the constructed state.
+    link: usize,
+    /// Offset of the first object, after the header
+    offset: usize,
+    capacity: usize,
+    slab_size: usize,
+    /// Run on every object when its slab is created. Objects must be freed in
+    /// their constructed state.
+    ctor: Option<fn(*mut u8)>,
+    state: IRQLock<CacheState>,
+    registered: AtomicBool,
+    next: AtomicPtr<SlabCache>,
+}
+
+impl SlabCache {
+    pub(crate) const fn new(name: &'static str, layout: Layout, ctor: Option<fn(*mut u8)>) -> Self {
+        let align = if layout.align() > align_of::<FreeObject>() {
+            layout.align()
+        } else {
+            align_of::<FreeObject>()
+        };
+        let link = if ctor.is_some() {
+            (layout.size() + align_of::<FreeObject>() - 1) & !(align_of::<FreeObject>() - 1)
+        } else {
+            0
+        };
+        let size = if layout.size() > link + size_of::<FreeObject>() {
+            layout.size()
+        } else {
+            link + size_of::<FreeObject>()
+        };
+        let stride = (size + align - 1) & !(align - 1);
+        let offset = (size_of::<SlabHeader>() + align - 1) & !(align - 1);
+
+        let mut slab_size = Size4KiB::SIZE as usize;
+        while slab_size < offset + stride * MIN_OBJECTS_PER_SLAB {
+            slab_size *= 2;
+        }
+
+        Self {
+            name,
+            layout,
+            stride,
+            link,
+            offset,
+            capacity: (slab_size - offset) / stride,
+            slab_size,
+            ctor,
+            state: IRQLock::new(CacheState {
+                partial: SlabList::new(),
+                full: SlabList::new(),
+                slabs: 0,
+                empty: 0,
+                in_use: 0,
+            }),
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// A cache for values of type `T`
+    pub(crate) const fn of<T>(name: &'static str) -> Self {
+        Self::new(name, Layout::new::<T>(), None)
+    }
+
+    fn frames(&self) -> usize {
+        self.slab_size / Size4KiB::SIZE as usize
+    }
+
+    /// Adds this cache to the list walked by `caches`
+    fn register(&'static self) {
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let this = self as *const Self as *mut Self;
+        let mut head = CACHES.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Release);
+            match CACHES.compare_exchange(head, this, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Creates a slab with every object free
+    unsafe fn grow(&self, state: &mut CacheState) -> Option<()> {
+        let frames = frame::allocate_contiguous(self.frames(), self.frames())?;
+        let base = mm::phys_to_virt(frames.start.start_address()).as_mut_ptr::<u8>();
+
+        let slab = base as *mut SlabHeader;
+        slab.write(SlabHeader {
+            free: ptr::null_mut(),
+            in_use: 0,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+        });
+
+        // Built back to front so that objects are handed out in address order
+        for i in (0..self.capacity).rev() {
+            let object = base.add(self.offset + i * self.stride);
+            if let Some(ctor) = self.ctor {
+                ctor(object);
+            }
+
+            let link = object.add(self.link) as *mut FreeObject;
+            (*link).next = (*slab).free;
+            (*slab).free = link;
+        }
+
+        state.partial.push(slab);
+        state.slabs += 1;
+        state.empty += 1;
+
+        Some(())
+    }
+
+    fn header(&self, object: *mut u8) -> *mut SlabHeader {
+        (object as usize & !(self.slab_size - 1)) as *mut SlabHeader
+    }
+
+    pub(crate) fn alloc(&'static self) -> Option<NonNull<u8>> {
+        let mut state = self.state.lock();
+
+        unsafe {
+            if state.partial.head.is_null() {
+                self.register();
+                self.grow(&mut state)?;
+            }
+
+            let slab = state.partial.head;
+            let link = (*slab).free;
+            (*slab).free = (*link).next;
+
+            if (*slab).in_use == 0 {
+                state.empty -= 1;
+            }
+            (*slab).in_use += 1;
+            state.in_use += 1;
+
+            if (*slab).free.is_null() {
+                state.partial.remove(slab);
+                state.full.push(slab);
+            }
+
+            NonNull::new((link as *mut u8).sub(self.link))
+        }
+    }
+
+    /// Returns an object to the cache
+    ///
+    /// # Safety
+    /// `object` must have come from `alloc` of this cache.
+    pub(crate) unsafe fn free(&self, object: NonNull<u8>) {
+        let mut state = self.state.lock();
+        let slab = self.header(object.as_ptr());
+
+        if (*slab).free.is_null() {
+            state.full.remove(slab);
+            state.partial.push(slab);
+        }
+
+        let link = object.as_ptr().add(self.link) as *mut FreeObject;
+        (*link).next = (*slab).free;
+        (*slab).free = link;
+        (*slab).in_use -= 1;
+        state.in_use -= 1;
+
+        if (*slab).in_use == 0 {
+            // One empty slab is kept so that an object bouncing between
+            // allocated and free does not create a slab every time
+            if state.empty > 0 {
+                state.partial.remove(slab);
+                state.slabs -= 1;
+
+                let start =
+                    PhysFrame::containing_address(mm::virt_to_phys(VirtAddr::from_ptr(slab)));
+                frame::free(PhysFrameRange {
+                    start,
+                    end: start + self.frames() as u64,
+                });
+            } else {
+                state.empty += 1;
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let state = self.state.lock();
+
+        CacheStats {
+            name: self.name,
+            object_size: self.layout.size(),
+            in_use: state.in_use,
+            capacity: state.slabs * self.capacity,
+            slabs: state.slabs,
+            slab_size: self.slab_size,
+        }
+    }
+}
+
+unsafe impl Allocator for &'static SlabCache {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > self.layout.size() || layout.align() > self.layout.align() {
+            return Err(AllocError);
+        }
+
+        let object = (*self).alloc().ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(object, self.layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        self.free(ptr);
+    }
+}
+
+/// Every cache that has been used so far
+pub(crate) fn caches() -> impl Iterator<Item = &'static SlabCache> {
+    let mut next = CACHES.load(Ordering::Acquire);
+
+    core::iter::from_fn(move || {
+        let cache = unsafe { next.as_ref()? };
+        next = cache.next.load(Ordering::Acquire);
+        Some(cache)
+    })
+}
+
+pub(crate) fn log_summary() {
+    for stats in caches().map(SlabCache::stats) {
+        info!(
+            "Slab {}: {} of {} objects in use, {} slabs of {} KiB, {}% fragmented",
+            stats.name,
+            stats.in_use,
+            stats.capacity,
+            stats.slabs,
+            stats.slab_size / 1024,
+            stats.fragmentation()
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use boot_lib::{KERNEL_STACK_CANARY, KERNEL_STACK_CANARY_WORDS};
+use core::{
+    ops::Range,
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use log::{error, info};
+use x86_64::VirtAddr;
+
+use crate::boot;
+
+/// Set once an interrupt handler found the canary overwritten
+static CANARY_OVERWRITTEN: AtomicBool = AtomicBool::new(false);
+
+/// The boot kernel stack, without the guard area
+pub(crate) fn range() -> Range<VirtAddr> {
+    let layout = boot::layout();
+    let bottom = VirtAddr::new(layout.stack_bottom);
+
+    (bottom - layout.stack_size)..bottom
+}
+
+/// The unmapped area directly below the boot kernel stack
+pub(crate) fn guard() -> Range<VirtAddr> {
+    let limit = range().start;
+
+    (limit - boot::layout().stack_guard_size)..limit
+}
+
+/// Whether a fault at `addr` means the boot kernel stack overflowed
+pub(crate) fn is_guard_hit(addr: VirtAddr) -> bool {
+    guard().contains(&addr)
+}
+
+/// Whether the canary at the limit of the boot kernel stack is untouched. An
+/// overwritten canary means the stack came within a few bytes of overflowing,
+/// or something wrote through a stray pointer.
+pub(crate) fn canary_intact() -> bool {
+    let canary =
+        unsafe { slice::from_raw_parts(range().start.as_ptr::<u64>(), KERNEL_STACK_CANARY_WORDS) };
+
+    canary.iter().all(|&word| word == KERNEL_STACK_CANARY)
+}
+
+/// Checks the canary from an interrupt handler, which must not panic. An
+/// overwritten canary is reported once and left to `check_canary`.
+pub(crate) fn watch_canary() {
+    if !canary_intact() && !CANARY_OVERWRITTEN.swap(true, Ordering::Relaxed) {
+        error!("Kernel stack canary overwritten, the stack nearly overflowed");
+    }
+}
+
+/// Panics if the canary is overwritten or an interrupt handler found it so.
+/// For code outside interrupt handlers.
+pub(crate) fn check_canary() {
+    if CANARY_OVERWRITTEN.load(Ordering::Relaxed) || !canary_intact() {
+        panic!("Kernel stack canary overwritten, the stack nearly overflowed");
+    }
+}
+
+pub(crate) fn init() {
+    let stack = range();
+    let guard = guard();
+
+    info!(
+        "Kernel stack {:?} - {:?}, guard {:?} - {:?}",
+        stack.start, stack.end, guard.start, guard.end
+    );
+
+    assert!(canary_intact(), "Kernel stack canary missing at boot");
+}
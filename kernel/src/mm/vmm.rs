@@ -0,0 +1,544 @@
+use core::{
+    arch::x86_64::__cpuid,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::paging::{
+        mapper::{MapToError, TranslateResult, UnmapError},
+        Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    boot,
+    data::{IRQLock, LateInit},
+    mm::{self, frame},
+};
+
+/// Above this many entries a full TLB flush is cheaper than flushing each one
+const TLB_FLUSH_ALL_THRESHOLD: usize = 64;
+
+/// Bumped for every TLB shootdown. Once there are other CPUs, each of them
+/// will compare it against the last generation it flushed for.
+static TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+static KERNEL_SPACE: LateInit<IRQLock<AddressSpace>> = LateInit::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MapError {
+    /// An address or size is not a multiple of 4K
+    Misaligned,
+    AlreadyMapped(VirtAddr),
+    NotMapped(VirtAddr),
+    /// The range covers only part of a 2M or 1G page
+    PartialHugePage(VirtAddr),
+    OutOfMemory,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Misaligned => f.write_str("range is not page aligned"),
+            MapError::AlreadyMapped(addr) => write!(f, "{:?} is already mapped", addr),
+            MapError::NotMapped(addr) => write!(f, "{:?} is not mapped", addr),
+            MapError::PartialHugePage(addr) => {
+                write!(f, "range covers only part of the huge page at {:?}", addr)
+            }
+            MapError::OutOfMemory => f.write_str("out of physical memory"),
+        }
+    }
+}
+
+/// Page table entries that need flushing once an operation is done. A huge
+/// page is a single entry, one `invlpg` anywhere in it evicts all of it.
+struct TlbBatch {
+    pages: [VirtAddr; TLB_FLUSH_ALL_THRESHOLD],
+    len: usize,
+    overflow: bool,
+}
+
+impl TlbBatch {
+    fn new() -> Self {
+        Self {
+            pages: [VirtAddr::zero(); TLB_FLUSH_ALL_THRESHOLD],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    fn add(&mut self, page: VirtAddr) {
+        if self.len < self.pages.len() {
+            self.pages[self.len] = page;
+            self.len += 1;
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    /// Flushes the collected entries if the address space is loaded
+    fn flush(self, active: bool) {
+        if !active || self.len == 0 {
+            return;
+        }
+
+        TLB_GENERATION.fetch_add(1, Ordering::Release);
+
+        if self.overflow {
+            flush_global();
+        } else {
+            for &page in &self.pages[..self.len] {
+                tlb::flush(page);
+            }
+        }
+    }
+}
+
+/// A set of page tables, edited through the physical memory window
+pub(crate) struct AddressSpace {
+    pml4: PhysFrame,
+    huge_1gib: bool,
+}
+
+impl AddressSpace {
+    /// Wraps the page tables the bootloader left in CR3
+    fn active() -> Self {
+        let (pml4, _) = Cr3::read();
+
+        Self {
+            pml4,
+            // CPUID.80000001h:EDX.Page1GB
+            huge_1gib: unsafe { __cpuid(0x8000_0001).edx } & (1 << 26) != 0,
+        }
+    }
+
+    /// An address space with empty lower half and the kernel's upper half,
+    /// for user processes
+    pub(crate) fn new() -> Result<Self, MapError> {
+        let kernel = kernel().lock();
+        let pml4 = frame::allocate().ok_or(MapError::OutOfMemory)?;
+
+        unsafe {
+            let table = &mut *mm::phys_to_virt(pml4.start_address()).as_mut_ptr::<PageTable>();
+            let kernel_table =
+                &*mm::phys_to_virt(kernel.pml4.start_address()).as_ptr::<PageTable>();
+
+            table.zero();
+            for i in 256..512 {
+                table[i] = kernel_table[i].clone();
+            }
+        }
+
+        Ok(Self {
+            pml4,
+            huge_1gib: kernel.huge_1gib,
+        })
+    }
+
+    /// Gives every upper half PML4 entry a page table, so that the top level
+    /// never changes again and copies of it in other address spaces stay
+    /// complete
+    fn fill_upper_half(&mut self) {
+        let table =
+            unsafe { &mut *mm::phys_to_virt(self.pml4.start_address()).as_mut_ptr::<PageTable>() };
+
+        for i in 256..512 {
+            if table[i].is_unused() {
+                let frame = frame::allocate_zeroed().expect("Out of memory for kernel page tables");
+                table[i].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            }
+        }
+    }
+
+    /// Drops the low half, which after handoff only holds the firmware's
+    /// identity map, so that stray low accesses fault. The page tables behind
+    /// it are left to `frame::reclaim`.
+    fn unmap_lower_half(&mut self) {
+        unsafe {
+            let table = &mut *mm::phys_to_virt(self.pml4.start_address()).as_mut_ptr::<PageTable>();
+            for i in 0..256 {
+                table[i].set_unused();
+            }
+        }
+
+        // Firmware mappings may be global
+        if self.is_active() {
+            TLB_GENERATION.fetch_add(1, Ordering::Release);
+            flush_global();
+        }
+    }
+
+    pub(crate) fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    fn page_table(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            let pml4 = &mut *mm::phys_to_virt(self.pml4.start_address()).as_mut_ptr::<PageTable>();
+            OffsetPageTable::new(pml4, VirtAddr::new(boot::layout().phys_map_offset))
+        }
+    }
+
+    /// Maps `size` bytes at `virt` to `phys`, using the largest pages that the
+    /// alignment of both allows. On failure the part mapped so far stays
+    /// mapped.
+    pub(crate) fn map(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        if !virt.is_aligned(Size4KiB::SIZE)
+            || !phys.is_aligned(Size4KiB::SIZE)
+            || size % Size4KiB::SIZE != 0
+        {
+            return Err(MapError::Misaligned);
+        }
+
+        let huge_1gib = self.huge_1gib;
+        let mut page_table = self.page_table();
+        let mut done = 0;
+
+        while done < size {
+            let (virt, phys, left) = (virt + done, phys + done, size - done);
+            let fits = |page_size: u64| {
+                virt.is_aligned(page_size) && phys.is_aligned(page_size) && left >= page_size
+            };
+
+            // New entries were not present before, so nothing is cached that
+            // needs flushing
+            done += unsafe {
+                if huge_1gib && fits(Size1GiB::SIZE) {
+                    map_page::<Size1GiB>(&mut page_table, virt, phys, flags)?
+                } else if fits(Size2MiB::SIZE) {
+                    map_page::<Size2MiB>(&mut page_table, virt, phys, flags)?
+                } else {
+                    map_page::<Size4KiB>(&mut page_table, virt, phys, flags)?
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Maps `size` bytes at `virt` to freshly allocated frames, which are
+    /// not zeroed
+    pub(crate) fn map_fresh(
+        &mut self,
+        virt: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        if !virt.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let mut page_table = self.page_table();
+        for offset in (0..size).step_by(Size4KiB::SIZE as usize) {
+            let frame = frame::allocate().ok_or(MapError::OutOfMemory)?;
+
+            if let Err(e) = unsafe {
+                map_page::<Size4KiB>(&mut page_table, virt + offset, frame.start_address(), flags)
+            } {
+                unsafe { frame::free(PhysFrame::range(frame, frame + 1)) };
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmaps `size` bytes at `virt`. Huge pages must be covered completely.
+    pub(crate) fn unmap(&mut self, virt: VirtAddr, size: u64) -> Result<(), MapError> {
+        self.unmap_with(virt, size, |_| {})
+    }
+
+    /// Unmaps `size` bytes at `virt` and frees the frames behind them, for
+    /// ranges set up with `map_fresh`
+    pub(crate) fn unmap_and_free(&mut self, virt: VirtAddr, size: u64) -> Result<(), MapError> {
+        self.unmap_with(virt, size, |frame| unsafe {
+            frame::free(PhysFrame::range(frame, frame + 1))
+        })
+    }
+
+    fn unmap_with(
+        &mut self,
+        virt: VirtAddr,
+        size: u64,
+        mut release: impl FnMut(PhysFrame),
+    ) -> Result<(), MapError> {
+        if !virt.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let active = self.is_active();
+        let mut page_table = self.page_table();
+        let mut batch = TlbBatch::new();
+        let mut done = 0;
+
+        let result = loop {
+            if done >= size {
+                break Ok(());
+            }
+
+            let addr = virt + done;
+            let page_size = match page_table.translate(addr) {
+                TranslateResult::Mapped { frame, .. } => frame.size(),
+                _ => break Err(MapError::NotMapped(addr)),
+            };
+            if !addr.is_aligned(page_size) || size - done < page_size {
+                break Err(MapError::PartialHugePage(addr));
+            }
+
+            let unmapped = match page_size {
+                Size4KiB::SIZE => page_table
+                    .unmap(Page::<Size4KiB>::containing_address(addr))
+                    .map(|(frame, flush)| {
+                        flush.ignore();
+                        release(frame);
+                    }),
+                Size2MiB::SIZE => page_table
+                    .unmap(Page::<Size2MiB>::containing_address(addr))
+                    .map(|(_, flush)| flush.ignore()),
+                _ => page_table
+                    .unmap(Page::<Size1GiB>::containing_address(addr))
+                    .map(|(_, flush)| flush.ignore()),
+            };
+            if let Err(e) = unmapped {
+                break Err(unmap_error(e, addr));
+            }
+
+            batch.add(addr);
+            done += page_size;
+        };
+
+        batch.flush(active);
+        result
+    }
+
+    /// Changes the flags of every page in the range. Huge pages must be
+    /// covered completely.
+    pub(crate) fn protect(
+        &mut self,
+        virt: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        self.update_flags(virt, size, |_| flags)
+    }
+
+    /// Replaces the flags of every page in the range with what `update`
+    /// returns for its current ones. Huge pages must be covered completely.
+    pub(crate) fn update_flags(
+        &mut self,
+        virt: VirtAddr,
+        size: u64,
+        update: impl Fn(PageTableFlags) -> PageTableFlags,
+    ) -> Result<(), MapError> {
+        if !virt.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let active = self.is_active();
+        let mut page_table = self.page_table();
+        let mut batch = TlbBatch::new();
+        let mut done = 0;
+
+        let result = loop {
+            if done >= size {
+                break Ok(());
+            }
+
+            let addr = virt + done;
+            let (page_size, flags) = match page_table.translate(addr) {
+                TranslateResult::Mapped { frame, flags, .. } => (frame.size(), update(flags)),
+                _ => break Err(MapError::NotMapped(addr)),
+            };
+            if !addr.is_aligned(page_size) || size - done < page_size {
+                break Err(MapError::PartialHugePage(addr));
+            }
+
+            let updated = unsafe {
+                match page_size {
+                    Size4KiB::SIZE => page_table
+                        .update_flags(Page::<Size4KiB>::containing_address(addr), flags)
+                        .map(|flush| flush.ignore()),
+                    Size2MiB::SIZE => page_table
+                        .update_flags(Page::<Size2MiB>::containing_address(addr), flags)
+                        .map(|flush| flush.ignore()),
+                    _ => page_table
+                        .update_flags(Page::<Size1GiB>::containing_address(addr), flags)
+                        .map(|flush| flush.ignore()),
+                }
+            };
+            if updated.is_err() {
+                break Err(MapError::NotMapped(addr));
+            }
+
+            batch.add(addr);
+            done += page_size;
+        };
+
+        batch.flush(active);
+        result
+    }
+
+    /// Splits the huge pages the range covers only in part into smaller pages
+    /// with the same flags, so that `protect` can change just the range
+    pub(crate) fn split_huge_pages(&mut self, virt: VirtAddr, size: u64) -> Result<(), MapError> {
+        if !virt.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+            return Err(MapError::Misaligned);
+        }
+
+        let active = self.is_active();
+        let mut batch = TlbBatch::new();
+
+        let result = [virt, virt + size].into_iter().try_for_each(|addr| {
+            // A 1G page splits into 2M pages, one of which may need splitting
+            // again
+            loop {
+                let page_size = match self.page_table().translate(addr) {
+                    TranslateResult::Mapped { frame, .. } => frame.size(),
+                    _ => return Ok(()),
+                };
+                if page_size == Size4KiB::SIZE || addr.is_aligned(page_size) {
+                    return Ok(());
+                }
+
+                self.split(addr, page_size)?;
+                batch.add(addr);
+            }
+        });
+
+        batch.flush(active);
+        result
+    }
+
+    /// Replaces the huge page of `page_size` at `addr` with a table of pages
+    /// of the next smaller size, mapping the same memory
+    fn split(&mut self, addr: VirtAddr, page_size: u64) -> Result<(), MapError> {
+        let table_frame = frame::allocate().ok_or(MapError::OutOfMemory)?;
+
+        let mut table =
+            unsafe { &mut *mm::phys_to_virt(self.pml4.start_address()).as_mut_ptr::<PageTable>() };
+        let depth = if page_size == Size1GiB::SIZE { 1 } else { 2 };
+        for &index in [addr.p4_index(), addr.p3_index()].iter().take(depth) {
+            table =
+                unsafe { &mut *mm::phys_to_virt(table[index].addr()).as_mut_ptr::<PageTable>() };
+        }
+        let entry = if page_size == Size1GiB::SIZE {
+            &mut table[addr.p3_index()]
+        } else {
+            &mut table[addr.p2_index()]
+        };
+
+        // In a 4K page entry the huge page bit selects the PAT instead
+        let small_size = page_size / 512;
+        let small_flags = if small_size == Size4KiB::SIZE {
+            entry.flags() - PageTableFlags::HUGE_PAGE
+        } else {
+            entry.flags()
+        };
+
+        let small = unsafe {
+            &mut *mm::phys_to_virt(table_frame.start_address()).as_mut_ptr::<PageTable>()
+        };
+        for (i, small_entry) in small.iter_mut().enumerate() {
+            small_entry.set_addr(entry.addr() + i as u64 * small_size, small_flags);
+        }
+
+        entry.set_frame(
+            table_frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+
+        Ok(())
+    }
+
+    /// The physical address and flags `virt` is mapped with
+    pub(crate) fn translate(&mut self, virt: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.page_table().translate(virt) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+}
+
+unsafe fn map_page<S: PageSize>(
+    page_table: &mut OffsetPageTable,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<u64, MapError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    page_table
+        .map_to(
+            Page::<S>::containing_address(virt),
+            PhysFrame::<S>::containing_address(phys),
+            flags,
+            &mut frame::GlobalFrameAllocator,
+        )
+        .map(|flush| flush.ignore())
+        .map_err(|e| match e {
+            MapToError::FrameAllocationFailed => MapError::OutOfMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                MapError::AlreadyMapped(virt)
+            }
+        })?;
+
+    Ok(S::SIZE)
+}
+
+/// Flushes the whole TLB, global entries such as the physical memory window
+/// included, which `tlb::flush_all` leaves alone
+fn flush_global() {
+    if Cr4::read().contains(Cr4Flags::PAGE_GLOBAL) {
+        unsafe {
+            Cr4::update(|flags| flags.remove(Cr4Flags::PAGE_GLOBAL));
+            Cr4::update(|flags| flags.insert(Cr4Flags::PAGE_GLOBAL));
+        }
+    } else {
+        tlb::flush_all();
+    }
+}
+
+fn unmap_error(e: UnmapError, addr: VirtAddr) -> MapError {
+    match e {
+        UnmapError::ParentEntryHugePage => MapError::PartialHugePage(addr),
+        _ => MapError::NotMapped(addr),
+    }
+}
+
+/// The kernel's address space, the one every other address space shares its
+/// upper half with
+pub(crate) fn kernel() -> &'static IRQLock<AddressSpace> {
+    &KERNEL_SPACE
+}
+
+/// Shootdowns done so far
+pub(crate) fn tlb_generation() -> u64 {
+    TLB_GENERATION.load(Ordering::Acquire)
+}
+
+pub(crate) fn init() {
+    let mut kernel = AddressSpace::active();
+    kernel.fill_upper_half();
+    kernel.unmap_lower_half();
+
+    KERNEL_SPACE.init(|| IRQLock::new(kernel));
+}
@@ -0,0 +1,173 @@
+use alloc::vec::Vec;
+use boot_lib::{AcpiRsdpInfo, SmbiosInfo};
+use core::{ops::Range, ptr};
+use log::{info, warn};
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    PhysAddr,
+};
+
+use crate::{boot, mm};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+/// Size of the header every ACPI table after the RSDP starts with
+const SDT_HEADER_SIZE: u64 = 36;
+/// Anything longer is taken for garbage rather than a table
+const MAX_TABLE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The ACPI RSDP, the starting point for discovering APICs, HPET, PCIe ECAM
+/// and power management
+pub(crate) fn acpi_rsdp() -> Option<&'static AcpiRsdpInfo> {
+    boot::args().acpi_rsdp()
+}
+
+/// The SMBIOS entry point, describing the machine and its firmware
+pub(crate) fn smbios() -> Option<&'static SmbiosInfo> {
+    boot::args().smbios()
+}
+
+/// Whether `len` bytes at `phys` can be read through the physical memory
+/// window, addresses in firmware tables are not trusted
+fn in_window(phys: u64, len: u64) -> bool {
+    phys != 0
+        && phys
+            .checked_add(len)
+            .map_or(false, |end| end <= boot::layout().phys_map_size)
+}
+
+unsafe fn read<T: Copy>(phys: u64) -> T {
+    ptr::read_unaligned(mm::phys_to_virt(PhysAddr::new(phys)).as_ptr::<T>())
+}
+
+/// The range of the ACPI table at `phys`, per the length in its header
+fn sdt_range(phys: u64) -> Option<Range<u64>> {
+    if !in_window(phys, SDT_HEADER_SIZE) {
+        return None;
+    }
+
+    let len = unsafe { read::<u32>(phys + 4) as u64 };
+    ((SDT_HEADER_SIZE..=MAX_TABLE_SIZE).contains(&len) && in_window(phys, len))
+        .then(|| phys..phys + len)
+}
+
+/// The physical memory holding the ACPI and SMBIOS tables the accessors here
+/// lead to, which has to stay allocated when boot memory is reclaimed.
+/// Covers the RSDP, the RSDT or XSDT and every table it lists, the DSDT and
+/// FACS, and the SMBIOS entry point and structure table.
+pub(crate) fn firmware_tables() -> Vec<Range<PhysAddr>> {
+    let mut tables: Vec<Range<u64>> = Vec::new();
+
+    let rsdp = acpi_rsdp().filter(|rsdp| {
+        in_window(rsdp.phys_addr, SDT_HEADER_SIZE)
+            && unsafe { read::<[u8; 8]>(rsdp.phys_addr) } == *RSDP_SIGNATURE
+    });
+    if let Some(rsdp) = rsdp {
+        let phys = rsdp.phys_addr;
+        let (root, entry_size) = if rsdp.revision >= 2 {
+            let len = unsafe { read::<u32>(phys + 20) as u64 };
+            tables.push(phys..phys + len.clamp(SDT_HEADER_SIZE, Size4KiB::SIZE));
+            (unsafe { read::<u64>(phys + 24) }, 8)
+        } else {
+            tables.push(phys..phys + 20);
+            (unsafe { read::<u32>(phys + 16) as u64 }, 4)
+        };
+
+        if let Some(root) = sdt_range(root) {
+            let entries = (root.start + SDT_HEADER_SIZE..root.end).step_by(entry_size);
+            tables.push(root.clone());
+
+            for entry in entries.filter(|entry| entry + entry_size as u64 <= root.end) {
+                let table = match entry_size {
+                    8 => unsafe { read::<u64>(entry) },
+                    _ => unsafe { read::<u32>(entry) as u64 },
+                };
+                let table = match sdt_range(table) {
+                    Some(table) => table,
+                    None => continue,
+                };
+
+                // The FADT points to the DSDT and FACS, preferring the 64 bit
+                // fields where they are present and set
+                if unsafe { read::<[u8; 4]>(table.start) } == *FADT_SIGNATURE {
+                    let field = |offset32: u64, offset64: u64| {
+                        let wide = if table.start + offset64 + 8 <= table.end {
+                            unsafe { read::<u64>(table.start + offset64) }
+                        } else {
+                            0
+                        };
+                        if wide != 0 {
+                            wide
+                        } else {
+                            unsafe { read::<u32>(table.start + offset32) as u64 }
+                        }
+                    };
+                    tables.extend(sdt_range(field(40, 140)));
+                    tables.extend(sdt_range(field(36, 132)));
+                }
+
+                tables.push(table);
+            }
+        }
+    }
+
+    // Both entry point layouts are at most 0x20 bytes
+    if let Some(smbios) = smbios().filter(|smbios| in_window(smbios.phys_addr, 0x20)) {
+        let phys = smbios.phys_addr;
+        let (len, table, table_len) = if smbios.major_version >= 3 {
+            unsafe {
+                (
+                    read::<u8>(phys + 6) as u64,
+                    read::<u64>(phys + 0x10),
+                    read::<u32>(phys + 0x0C) as u64,
+                )
+            }
+        } else {
+            unsafe {
+                (
+                    read::<u8>(phys + 5) as u64,
+                    read::<u32>(phys + 0x18) as u64,
+                    read::<u16>(phys + 0x16) as u64,
+                )
+            }
+        };
+
+        tables.push(phys..phys + len);
+        if in_window(table, table_len) {
+            tables.push(table..table + table_len);
+        }
+    }
+
+    tables
+        .into_iter()
+        .filter(|table| !table.is_empty())
+        .map(|table| PhysAddr::new(table.start)..PhysAddr::new(table.end))
+        .collect()
+}
+
+pub(crate) fn init() {
+    match acpi_rsdp() {
+        Some(rsdp) => {
+            let signature =
+                unsafe { &*mm::phys_to_virt(PhysAddr::new(rsdp.phys_addr)).as_ptr::<[u8; 8]>() };
+
+            if signature == RSDP_SIGNATURE {
+                info!(
+                    "ACPI RSDP (revision {}) at {:#x}",
+                    rsdp.revision, rsdp.phys_addr
+                );
+            } else {
+                warn!("ACPI RSDP at {:#x} has a bad signature", rsdp.phys_addr);
+            }
+        }
+        None => warn!("No ACPI RSDP, platform discovery is unavailable"),
+    }
+
+    if let Some(smbios) = smbios() {
+        info!(
+            "SMBIOS {} entry point at {:#x}",
+            smbios.major_version, smbios.phys_addr
+        );
+    }
+}
@@ -1,16 +1,23 @@
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
 use core::{
     future::Future,
     pin::Pin,
-    task::{Context, Poll, Waker},
+    sync::atomic::{fence, AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
 };
 
 use crossbeam_queue::ArrayQueue;
 
-use super::{Task, TaskId};
+use super::{Task, TaskId, TASK_CACHE};
+use crate::mm::{self, slab::SlabCache};
+
+static WAKER_CACHE: SlabCache = SlabCache::of::<TaskWaker>("task-waker");
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_waker, wake_waker_by_ref, drop_waker);
 
 pub(crate) struct Executor<'a> {
-    tasks: BTreeMap<TaskId, Task<'a>>,
+    tasks: BTreeMap<TaskId, Box<Task<'a>, &'static SlabCache>>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     waker_cache: BTreeMap<TaskId, Waker>,
 }
@@ -27,7 +34,11 @@ impl<'a> Executor<'a> {
 
     pub(crate) fn spawn(&mut self, task: Task<'a>) {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+        if self
+            .tasks
+            .insert(task.id, Box::new_in(task, &TASK_CACHE))
+            .is_some()
+        {
             panic!("task with same ID already in tasks");
         }
         self.task_queue.push(task_id).expect("queue full");
@@ -36,6 +47,8 @@ impl<'a> Executor<'a> {
     pub(crate) fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
+            // Outside of interrupt handlers, where panicking is safe
+            mm::stack::check_canary();
             self.sleep_if_idle();
         }
     }
@@ -43,7 +56,7 @@ impl<'a> Executor<'a> {
     fn run_ready_tasks(&mut self) {
         while let Some(task_id) = self.task_queue.pop() {
             let task = Pin::new(match self.tasks.get_mut(&task_id) {
-                Some(task) => task,
+                Some(task) => &mut **task,
                 None => continue, // task no longer exists
             });
 
@@ -83,9 +96,11 @@ impl Default for Executor<'_> {
     }
 }
 
+/// Shared by every clone of a task's waker, freed with the last one
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    refs: AtomicUsize,
 }
 
 impl TaskWaker {
@@ -93,11 +108,17 @@ impl TaskWaker {
         Self {
             task_id,
             task_queue,
+            refs: AtomicUsize::new(1),
         }
     }
 
+    fn into_raw(self) -> RawWaker {
+        let (waker, _) = Box::into_raw_with_allocator(Box::new_in(self, &WAKER_CACHE));
+        RawWaker::new(waker as *const (), &WAKER_VTABLE)
+    }
+
     fn into_waker(self) -> Waker {
-        Waker::from(Arc::new(self))
+        unsafe { Waker::from_raw(self.into_raw()) }
     }
 
     fn wake_task(&self) {
@@ -111,12 +132,32 @@ impl From<TaskWaker> for Waker {
     }
 }
 
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
-    }
+// A waker is a reference counted object from `WAKER_CACHE`, the same
+// scheme `Arc` uses, so cloning one only bumps the count
+
+unsafe fn clone_waker(waker: *const ()) -> RawWaker {
+    (*(waker as *const TaskWaker))
+        .refs
+        .fetch_add(1, Ordering::Relaxed);
+    RawWaker::new(waker, &WAKER_VTABLE)
+}
+
+unsafe fn wake_waker(waker: *const ()) {
+    wake_waker_by_ref(waker);
+    drop_waker(waker);
+}
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+unsafe fn wake_waker_by_ref(waker: *const ()) {
+    (*(waker as *const TaskWaker)).wake_task();
+}
+
+unsafe fn drop_waker(waker: *const ()) {
+    let refs = &(*(waker as *const TaskWaker)).refs;
+    if refs.fetch_sub(1, Ordering::Release) != 1 {
+        return;
     }
+
+    // Everything the other clones did happens before the object goes
+    fence(Ordering::Acquire);
+    drop(Box::from_raw_in(waker as *mut TaskWaker, &WAKER_CACHE));
 }
@@ -7,8 +7,13 @@ use core::{
     task::{Context, Poll},
 };
 
+use crate::mm::slab::SlabCache;
+
 pub(crate) mod executor;
 
+/// Tasks are kept by the executor in objects from this cache
+static TASK_CACHE: SlabCache = SlabCache::of::<Task<'static>>("task");
+
 /// A task that can be executed with an [`Executor`](executor::Executor).
 pub(crate) struct Task<'a> {
     pub(crate) id: TaskId,
@@ -11,10 +11,26 @@ fn main() {
     let target_dir = kernel_binary.parent().unwrap();
     let esp_dir = &target_dir.join("esp");
     let efi_boot_dir = &esp_dir.join("EFI").join("Boot");
+    let efi_tyto_dir = &esp_dir.join("EFI").join("tyto");
     let out_dir = Path::new(env!("OUT_DIR"));
 
+    // The kernel ELF is built separately for x86_64-unknown-none with the same profile
+    let kernel_elf = env::var_os("TYTO_KERNEL")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            let profile = target_dir.file_name().unwrap();
+            target_dir
+                .join("../..")
+                .join("x86_64-unknown-none")
+                .join(profile)
+                .join("kernel")
+        });
+
     fs::create_dir_all(efi_boot_dir).unwrap();
+    fs::create_dir_all(efi_tyto_dir).unwrap();
     fs::copy(kernel_binary, efi_boot_dir.join("BootX64.efi")).unwrap();
+    fs::copy(&kernel_elf, efi_tyto_dir.join("kernel.elf"))
+        .unwrap_or_else(|e| panic!("Could not copy the kernel from {:?}: {}", kernel_elf, e));
 
     let mut qemu = Command::new("qemu-system-x86_64");
     qemu.arg("-drive")
@@ -23,7 +39,23 @@ fn main() {
         .arg(out_dir.join("OVMF.fd").to_str().unwrap());
     qemu.arg("-machine").arg("q35");
     qemu.arg("-serial").arg("stdio");
-    qemu.arg("-net").arg("none");
+    // TYTO_TFTP serves a directory, or EFI/tyto on the ESP if empty, over
+    // QEMU's built-in TFTP server for `tftp://` boot entries
+    match env::var_os("TYTO_TFTP") {
+        Some(dir) => {
+            let dir = if dir.is_empty() {
+                efi_tyto_dir.clone()
+            } else {
+                absolute(dir)
+            };
+            qemu.arg("-netdev")
+                .arg(format!("user,id=net0,tftp={}", dir.display()));
+            qemu.arg("-device").arg("virtio-net-pci,netdev=net0");
+        }
+        None => {
+            qemu.arg("-net").arg("none");
+        }
+    }
     qemu.arg("-m").arg("256M");
     qemu.arg("-nodefaults");
     qemu.arg("-vga").arg("std");